@@ -0,0 +1,116 @@
+//! Compiles the programs next to this file and times the executables.
+//!
+//! Run with `cargo bench`. Besides the times it prints how many block addresses the IR takes,
+//! since those keep LLVM from merging and removing blocks. To compare with another revision,
+//! build its `bsc` and point `BSC_BASELINE` at it, the programs are then compiled with both.
+//! That `bsc` needs the stdlib.c prelude and `--emit`, older ones fail to compile the programs.
+
+use beatsaber::codegen::{CodegenOptions, Emit, OptLevel};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// Name, source and exit code, which is the result of the program truncated to a byte.
+const PROGRAMS: &[(&str, &str, i32)] = &[
+    ("fib", include_str!("fib.beatsaber"), 5),
+    ("multiply", include_str!("multiply.beatsaber"), 0),
+    ("multiply_goto", include_str!("multiply_goto.beatsaber"), 0),
+];
+
+const RUNS: usize = 10;
+
+fn output(outputs: &[(Emit, PathBuf)], emit: Emit) -> PathBuf {
+    outputs
+        .iter()
+        .find(|(kind, _)| *kind == emit)
+        .unwrap()
+        .1
+        .clone()
+}
+
+/// Compile `src` with this revision, returning the paths of the IR and the executable.
+fn compile(stem: &Path, name: &str, src: &str) -> (PathBuf, PathBuf) {
+    let options = CodegenOptions {
+        emit: vec![Emit::LlvmIr, Emit::Exe],
+        optimization: OptLevel::Aggressive,
+        ..CodegenOptions::new(stem)
+    };
+    let artifact = beatsaber::compile_str(src, name, 0, options)
+        .unwrap_or_else(|e| panic!("failed to compile {}: {}", name, e));
+    (
+        output(&artifact.outputs, Emit::LlvmIr),
+        output(&artifact.outputs, Emit::Exe),
+    )
+}
+
+/// Compile `src` like `compile`, with the `bsc` executable at `bsc`.
+fn compile_with(bsc: &Path, stem: &Path, name: &str, src: &str) -> (PathBuf, PathBuf) {
+    let source = stem.with_extension("beatsaber");
+    fs::write(&source, src).unwrap();
+    let status = Command::new(bsc)
+        .arg(&source)
+        .arg("--emit=llvm-ir,exe")
+        .arg("-O3")
+        .arg("-o")
+        .arg(stem)
+        .status()
+        .unwrap();
+    assert!(
+        status.success(),
+        "{} failed to compile {}, the baseline needs the stdlib.c prelude and --emit",
+        bsc.display(),
+        name
+    );
+    (stem.with_extension("ll"), stem.to_owned())
+}
+
+fn measure(
+    name: &str,
+    revision: &str,
+    exit_code: i32,
+    compile: impl FnOnce() -> (PathBuf, PathBuf),
+) {
+    let start = Instant::now();
+    let (ir, exe) = compile();
+    let compile = start.elapsed();
+
+    let ir = fs::read_to_string(ir).unwrap();
+    let mut runs: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let status = Command::new(&exe).status().unwrap();
+            let elapsed = start.elapsed();
+            assert_eq!(status.code(), Some(exit_code), "{} {}", name, status);
+            elapsed
+        })
+        .collect();
+    runs.sort_unstable();
+
+    println!(
+        "{:<14} {:<8} compile {:>10.2?}  run {:>10.2?} (median of {})  {} block addresses, {} lines of IR",
+        name,
+        revision,
+        compile,
+        runs[RUNS / 2],
+        RUNS,
+        ir.matches("blockaddress(").count(),
+        ir.lines().count(),
+    );
+}
+
+fn main() {
+    let baseline = std::env::var_os("BSC_BASELINE").map(PathBuf::from);
+    let dir = std::env::temp_dir().join("beatsaber_bench");
+    fs::create_dir_all(dir.join("baseline")).unwrap();
+    for &(name, src, exit_code) in PROGRAMS {
+        if let Some(bsc) = &baseline {
+            let stem = dir.join("baseline").join(name);
+            measure(name, "baseline", exit_code, || {
+                compile_with(bsc, &stem, name, src)
+            });
+        }
+        let stem = dir.join(name);
+        measure(name, "current", exit_code, || compile(&stem, name, src));
+    }
+}
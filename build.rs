@@ -9,4 +9,15 @@ macro_rules! reexport_env {
 
 fn main() {
     reexport_env!("HOST");
+
+    // Linked into the compiler so `bsc run` can resolve the helpers in-process
+    if ::std::env::var_os("CARGO_FEATURE_LLVM").is_some() {
+        cc::Build::new()
+            .file("stdlib.c")
+            .file("runtime.c")
+            .compile("bsstd");
+    }
+    println!("cargo:rerun-if-changed=stdlib.c");
+    println!("cargo:rerun-if-changed=runtime.c");
+    println!("cargo:rerun-if-changed=build.rs");
 }
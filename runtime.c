@@ -0,0 +1,10 @@
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+/* Linked into every program, called when a computed goto has nowhere valid to go. */
+void bs_goto_failed(uintptr_t target, uintptr_t line) {
+    fprintf(stderr, "goto to invalid line %llu at line %llu\n", (unsigned long long)target,
+            (unsigned long long)line);
+    abort();
+}
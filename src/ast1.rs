@@ -27,6 +27,12 @@ pub enum Expr {
         expr: Box<Expr>,
         r: Span,
     },
+    /// `(a and b and c)`, the operands of an operation taking more than two parameters
+    Args {
+        l: Span,
+        args: Vec<Expr>,
+        r: Span,
+    },
     Ident(Span),
 }
 
@@ -47,6 +53,8 @@ pub enum Behaviour {
         is: Span,
         value: AssignValue,
     },
+    /// `here is name`, names the line it is on for `goto`
+    Label { here: Span, is: Span, name: Span },
 }
 
 #[derive(Debug)]
@@ -61,8 +69,13 @@ pub enum AssignTarget {
 pub struct NotHere {
     pub not_here: Span,
     pub but_is_in: Option<Span>,
+    /// Module after `but is in`, see `NotHere::module`
     pub ident: Option<Span>,
     pub and_is_big: Option<Span>,
+    /// Parameter count after `this is big`, two if there is none
+    pub params: Option<(Span, usize)>,
+    /// `or more`, the function takes more parameters after these
+    pub or_more: Option<Span>,
 }
 
 #[derive(Debug)]
@@ -84,19 +97,41 @@ pub struct Op {
 pub struct Fn {
     pub with: Span,
     pub params: FnParams,
+    /// `here for all`, the function is exported under its name for C code to call
+    pub export: Option<Span>,
     pub ops: Vec<Op>,
 }
 
 #[derive(Debug)]
 pub struct FnParams {
-    pub p1: Span,
-    pub and: Option<Span>,
-    pub p2: Option<Span>,
+    /// At least one name, separated by `and`
+    pub names: Vec<Span>,
+    pub ands: Vec<Span>,
 }
 
 pub struct Parser1<'a> {
     tokens: Lexer<'a>,
-    line: usize,
+}
+
+impl NotHere {
+    /// Name of the module after `but is in`, without the quotes if it is a string.
+    pub fn module<'a>(&self, src: &'a str) -> Option<&'a str> {
+        let module = &src[self.ident.clone()?];
+        Some(
+            module
+                .strip_prefix('"')
+                .map_or(module, |quoted| quoted.strip_suffix('"').unwrap_or(quoted)),
+        )
+    }
+
+    /// Number of parameters the external function takes.
+    pub fn param_count(&self) -> usize {
+        match (&self.and_is_big, &self.params) {
+            (_, Some((_, n))) => *n,
+            (Some(_), None) => 2,
+            (None, None) => 1,
+        }
+    }
 }
 
 impl Expr {
@@ -104,55 +139,36 @@ impl Expr {
         match self {
             Expr::Binop { lhs, rhs, .. } => (lhs.span().start)..(rhs.span().end),
             Expr::Unop { expr, op } => (expr.span().start)..(op.end),
-            Expr::Paren { l, r, .. } => (l.start)..(r.end),
+            Expr::Paren { l, r, .. } | Expr::Args { l, r, .. } => (l.start)..(r.end),
             Expr::Ident(ident) => ident.clone(),
         }
     }
 }
 
 impl Iterator for Parser1<'_> {
-    type Item = Stmt;
+    type Item = Result<Stmt, Diagnostic>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.tokens.peek() {
             Some((Token::Newline, _)) => {
                 self.tokens.next();
-                self.line += 1;
                 self.next()
             }
-            Some((Token::BehaviourStart, sep)) => {
-                self.tokens.next();
-                let behaviour = parse_behaviour(&mut self.tokens);
-                Some(Stmt {
-                    line: self.line,
-                    expr: None,
-                    sep,
-                    behaviour,
-                })
-            }
-            Some((Token::Identifier | Token::ParenLeft, _)) => {
-                let expr = parse_expr(&mut self.tokens);
-                let sep = self.tokens.monch(Token::BehaviourStart);
-                let behaviour = parse_behaviour(&mut self.tokens);
-                Some(Stmt {
-                    line: self.line,
-                    expr: Some(expr),
-                    sep,
-                    behaviour,
-                })
-            }
             None => None,
-            Some((t, s)) => self.tokens.reporter().report_and_exit(
-                &Diagnostic::error()
-                    .with_message(format!("unexpected token `{}` at statement start", t))
-                    .with_labels(vec![Label::primary((), s).with_message("not valid here")]),
-            ),
+            Some(_) => {
+                let line = self.tokens.line();
+                let stmt = self.parse_stmt(line);
+                if stmt.is_err() {
+                    self.recover(line);
+                }
+                Some(stmt)
+            }
         }
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
         let lines = self.tokens.src().split('\n').count();
-        let remaining = 1 + lines - self.line;
+        let remaining = 1 + lines - self.tokens.line();
         (0, Some(remaining))
     }
 }
@@ -165,88 +181,134 @@ impl<'a> Parser1<'a> {
     pub fn src(&self) -> &'a str {
         self.tokens.src()
     }
+
+    /// Comments in the statements parsed so far, see `Lexer::comments`.
+    pub fn comments(&self) -> &[(usize, Span)] {
+        self.tokens.comments()
+    }
+
+    /// Skip what is left of a statement that failed to parse, so parsing resumes on the next line.
+    fn recover(&mut self, line: usize) {
+        while self.tokens.line() == line && self.tokens.next().is_some() {}
+    }
+
+    fn parse_stmt(&mut self, line: usize) -> Result<Stmt, Diagnostic> {
+        match self.tokens.next_or_eof()? {
+            (Token::BehaviourStart, sep) => {
+                let behaviour = parse_behaviour(&mut self.tokens, false)?;
+                Ok(Stmt {
+                    line,
+                    expr: None,
+                    sep,
+                    behaviour,
+                })
+            }
+            (t @ (Token::Identifier | Token::ParenLeft), s) => {
+                let expr = parse_expr_start(t, s, &mut self.tokens)?;
+                let sep = self.tokens.monch(Token::BehaviourStart)?;
+                let behaviour = parse_behaviour(&mut self.tokens, true)?;
+                Ok(Stmt {
+                    line,
+                    expr: Some(expr),
+                    sep,
+                    behaviour,
+                })
+            }
+            (t, s) => Err(Diagnostic::error()
+                .with_message(format!("unexpected token `{}` at statement start", t))
+                .with_labels(vec![Label::primary((), s).with_message("not valid here")])),
+        }
+    }
 }
 
 pub fn parser(lexer: Lexer) -> Parser1 {
-    Parser1 {
-        tokens: lexer,
-        line: 1,
-    }
+    Parser1 { tokens: lexer }
+}
+
+fn parse_expr(tokens: &mut Lexer) -> Result<Expr, Diagnostic> {
+    let (token, span) = tokens.next_or_eof()?;
+    parse_expr_start(token, span, tokens)
 }
 
-fn parse_expr(tokens: &mut Lexer) -> Expr {
-    fn parse_expr_inner(expr: Expr, tokens: &mut Lexer) -> Expr {
-        let (token, op) = tokens.peek().unwrap();
-        match token {
-            Token::Operator => {
+/// Parse an expression whose first token has already been consumed.
+fn parse_expr_start(token: Token, span: Span, tokens: &mut Lexer) -> Result<Expr, Diagnostic> {
+    fn parse_expr_inner(expr: Expr, tokens: &mut Lexer) -> Result<Expr, Diagnostic> {
+        match tokens.peek() {
+            Some((Token::Operator, op)) => {
                 tokens.next();
-                match tokens.peek().unwrap() {
-                    (Token::Operator, _) => parse_expr_inner(
+                match tokens.peek() {
+                    Some((Token::Operator, _)) => parse_expr_inner(
                         Expr::Unop {
                             expr: Box::new(expr),
                             op,
                         },
                         tokens,
                     ),
-                    (Token::ParenRight | Token::BehaviourStart, _) => Expr::Unop {
+                    Some((Token::ParenRight | Token::BehaviourStart, _)) | None => Ok(Expr::Unop {
                         expr: Box::new(expr),
                         op,
-                    },
-                    _ => Expr::Binop {
+                    }),
+                    _ => Ok(Expr::Binop {
                         lhs: Box::new(expr),
                         op,
-                        rhs: Box::new(parse_expr(tokens)),
-                    },
+                        rhs: Box::new(parse_expr(tokens)?),
+                    }),
                 }
             }
-            _ => expr,
+            _ => Ok(expr),
         }
     }
 
-    match tokens.next().unwrap() {
+    match (token, span) {
         (Token::Identifier, ident) => parse_expr_inner(Expr::Ident(ident), tokens),
         (Token::ParenLeft, l) => {
-            let expr = parse_expr(tokens);
-            let r = tokens.monch(Token::ParenRight);
-            parse_expr_inner(
+            let expr = parse_expr(tokens)?;
+            let mut args = vec![expr];
+            while let Some((Token::And, _)) = tokens.peek() {
+                tokens.next();
+                args.push(parse_expr(tokens)?);
+            }
+            let r = tokens.monch(Token::ParenRight)?;
+            let expr = if args.len() == 1 {
                 Expr::Paren {
                     l,
-                    expr: Box::new(expr),
+                    expr: Box::new(args.pop().unwrap()),
                     r,
-                },
-                tokens,
-            )
+                }
+            } else {
+                Expr::Args { l, args, r }
+            };
+            parse_expr_inner(expr, tokens)
         }
-        (t, s) => tokens.reporter().report_and_exit(
-            &Diagnostic::bug()
-                .with_message(format!("unexpected token `{}`", t))
-                .with_labels(vec![Label::primary((), s).with_message("not valid here")]),
-        ),
+        (t, s) => Err(Diagnostic::bug()
+            .with_message(format!("unexpected token `{}`", t))
+            .with_labels(vec![Label::primary((), s).with_message("not valid here")])),
     }
 }
 
-fn parse_behaviour(tokens: &mut Lexer) -> Behaviour {
-    let (token, span) = tokens.next().unwrap();
+/// Parse what follows `//`, `expr` tells whether the statement has an expression before it.
+fn parse_behaviour(tokens: &mut Lexer, expr: bool) -> Result<Behaviour, Diagnostic> {
+    let (token, span) = tokens.next_or_eof()?;
     match token {
         Token::StillIn => {
             let still_in = span;
-            let ident = tokens.monch(Token::Identifier);
-            let behaviour = parse_behaviour(tokens);
-            Behaviour::StillIn {
+            let ident = tokens.monch(Token::Identifier)?;
+            let behaviour = parse_behaviour(tokens, expr)?;
+            Ok(Behaviour::StillIn {
                 still_in,
                 ident,
                 behaviour: Box::new(behaviour),
-            }
+            })
         }
         Token::If => {
             let if_ = span;
-            let cond = tokens.monch(Token::Identifier);
-            let behaviour = parse_behaviour(tokens);
-            Behaviour::Cond {
+            let cond = tokens.monch(Token::Identifier)?;
+            let behaviour = parse_behaviour(tokens, expr)?;
+            Ok(Behaviour::Cond {
                 if_,
                 cond,
                 behaviour: Box::new(behaviour),
-            }
+            })
         }
         Token::Identifier | Token::Discard | Token::Return | Token::Goto => {
             let target = match token {
@@ -256,129 +318,194 @@ fn parse_behaviour(tokens: &mut Lexer) -> Behaviour {
                 Token::Goto => AssignTarget::Goto(span),
                 _ => unreachable!(),
             };
-            let is = tokens.monch(Token::Is);
-            let value = parse_assign_value(tokens);
-            Behaviour::Assign { target, is, value }
+            let is = tokens.monch(Token::Is)?;
+            // `here is name` without an expression is a label, `here` is an ordinary name
+            // anywhere else
+            if let (false, AssignTarget::Ident(here), Some((Token::Identifier, name))) =
+                (expr, &target, tokens.peek())
+            {
+                if &tokens.src()[here.clone()] == "here" {
+                    tokens.next();
+                    return Ok(Behaviour::Label {
+                        here: here.clone(),
+                        is,
+                        name,
+                    });
+                }
+            }
+            let value = parse_assign_value(tokens)?;
+            Ok(Behaviour::Assign { target, is, value })
         }
-        _ => tokens.reporter().report_and_exit(
-            &Diagnostic::bug()
-                .with_message(format!("unexpected token `{}` in behaviour", token))
-                .with_labels(vec![Label::primary((), span).with_message("not valid here")]),
-        ),
+        _ => Err(Diagnostic::bug()
+            .with_message(format!("unexpected token `{}` in behaviour", token))
+            .with_labels(vec![Label::primary((), span).with_message("not valid here")])),
     }
 }
 
-fn parse_assign_value(tokens: &mut Lexer) -> AssignValue {
+fn parse_assign_value(tokens: &mut Lexer) -> Result<AssignValue, Diagnostic> {
     let value = match tokens.peek() {
-        Some((Token::Identifier, _)) => return AssignValue::Ops(parse_ops(tokens)),
-        Some((Token::With, _)) => return AssignValue::Fn(parse_fn(tokens)),
+        Some((Token::Identifier, _)) => return Ok(AssignValue::Ops(parse_ops(tokens)?)),
+        Some((Token::With, _)) => return Ok(AssignValue::Fn(parse_fn(tokens)?)),
         Some((Token::Number(n), span)) => AssignValue::Number(span, n),
         Some((Token::StringLiteral, span)) => AssignValue::String(span),
-        Some((Token::NotHere, _)) => return AssignValue::NotHere(parse_not_here(tokens)),
-        Some((Token::Newline, _)) | None => return AssignValue::Ops(Vec::new()),
-        Some((t, s)) => tokens.reporter().report_and_exit(
-            &Diagnostic::error()
+        Some((Token::NotHere, _)) => return Ok(AssignValue::NotHere(parse_not_here(tokens)?)),
+        Some((Token::Newline, _)) | None => return Ok(AssignValue::Ops(Vec::new())),
+        Some((t, s)) => {
+            return Err(Diagnostic::error()
                 .with_message(format!("unexpected token `{}` as assignment value", t))
-                .with_labels(vec![Label::primary((), s).with_message("not valid here")]),
-        ),
+                .with_labels(vec![Label::primary((), s).with_message("not valid here")]))
+        }
     };
     tokens.next();
-    value
+    Ok(value)
 }
 
-fn parse_not_here(tokens: &mut Lexer) -> NotHere {
-    let (_, not_here) = tokens.next().unwrap();
-    match tokens.peek() {
+fn parse_not_here(tokens: &mut Lexer) -> Result<NotHere, Diagnostic> {
+    let (_, not_here) = tokens.next_or_eof()?;
+    let (but_is_in, ident) = match tokens.peek() {
         Some((Token::ButIsIn, but_is_in)) => {
             tokens.next(); // Skip ButIsIn
-            let ident = tokens.monch(Token::Identifier);
-            let and_is_big = match tokens.peek() {
-                Some((Token::ThisIsBig, sp)) => {
-                    tokens.next();
-                    Some(sp)
-                }
-                _ => None,
-            };
-            NotHere {
-                not_here,
-                but_is_in: Some(but_is_in),
-                ident: Some(ident),
-                and_is_big,
-            }
+            let ident = parse_module(tokens)?;
+            (Some(but_is_in), Some(ident))
         }
-        _ => {
-            let and_is_big = match tokens.peek() {
-                Some((Token::ThisIsBig, sp)) => {
-                    tokens.next();
-                    Some(sp)
-                }
-                _ => None,
-            };
-            NotHere {
-                not_here,
-                but_is_in: None,
-                ident: None,
-                and_is_big,
+        _ => (None, None),
+    };
+    let and_is_big = match tokens.peek() {
+        Some((Token::ThisIsBig, sp)) => {
+            tokens.next();
+            Some(sp)
+        }
+        _ => None,
+    };
+    let params = match tokens.peek() {
+        Some((Token::Number(n), sp)) if and_is_big.is_some() => {
+            tokens.next();
+            Some((sp, n))
+        }
+        _ => None,
+    };
+    let or_more = match tokens.peek() {
+        Some((Token::OrMore, sp)) => {
+            tokens.next();
+            Some(sp)
+        }
+        _ => None,
+    };
+    Ok(NotHere {
+        not_here,
+        but_is_in,
+        ident,
+        and_is_big,
+        params,
+        or_more,
+    })
+}
+
+/// Parse a module name, a string or names and numbers joined by dots like `libthing.so.1`.
+fn parse_module(tokens: &mut Lexer) -> Result<Span, Diagnostic> {
+    if let Some((Token::StringLiteral, span)) = tokens.peek() {
+        tokens.next();
+        return Ok(span);
+    }
+    let mut module = tokens.monch(Token::Identifier)?;
+    while let Some((Token::Operator, dot)) = tokens.peek() {
+        if dot.start != module.end {
+            break;
+        }
+        tokens.next();
+        match tokens.next_or_eof()? {
+            (Token::Identifier | Token::Number(_), part) if part.start == dot.end => {
+                module.end = part.end
+            }
+            (t, s) => {
+                return Err(Diagnostic::error()
+                    .with_message(format!("unexpected token `{}` in module name", t))
+                    .with_labels(vec![Label::primary((), s).with_message("not valid here")])
+                    .with_notes(vec![
+                        "write module names that are not just names and dots as a string"
+                            .to_string(),
+                    ]))
             }
         }
     }
+    Ok(module)
 }
 
-fn parse_fn(tokens: &mut Lexer) -> Fn {
-    let (_, with) = tokens.next().unwrap();
-    let p1 = tokens.monch(Token::Identifier);
-    let (and, p2) = match tokens.peek() {
-        Some((Token::And, and)) => {
+fn parse_fn(tokens: &mut Lexer) -> Result<Fn, Diagnostic> {
+    let (_, with) = tokens.next_or_eof()?;
+    let mut names = vec![tokens.monch(Token::Identifier)?];
+    let mut ands = Vec::new();
+    while let Some((Token::And, and)) = tokens.peek() {
+        tokens.next();
+        ands.push(and);
+        names.push(tokens.monch(Token::Identifier)?);
+    }
+    // `here`, `for` and `all` stay usable as names, logos can't back off a longer keyword
+    let (export, ops) = match tokens.peek() {
+        Some((Token::Identifier, here)) if &tokens.src()[here.clone()] == "here" => {
             tokens.next();
-            let p2 = tokens.monch(Token::Identifier);
-            (Some(and), Some(p2))
+            match tokens.peek() {
+                Some((Token::Identifier, for_)) if &tokens.src()[for_.clone()] == "for" => {
+                    tokens.next();
+                    let all = tokens.monch_word("all")?;
+                    (Some(here.start..all.end), parse_ops(tokens)?)
+                }
+                // An operation named `here`
+                _ => (None, parse_ops_from(here, tokens)?),
+            }
         }
-        _ => (None, None),
+        _ => (None, parse_ops(tokens)?),
     };
-    let ops = parse_ops(tokens);
-    Fn {
+    Ok(Fn {
         with,
-        params: FnParams { p1, and, p2 },
+        params: FnParams { names, ands },
+        export,
         ops,
+    })
+}
+
+fn parse_ops(tokens: &mut Lexer) -> Result<Vec<Op>, Diagnostic> {
+    match tokens.peek() {
+        Some((Token::Identifier, ident)) => {
+            tokens.next();
+            parse_ops_from(ident, tokens)
+        }
+        Some((Token::Newline, _)) | None => Ok(Vec::new()),
+        Some((token, span)) => Err(unexpected_op(token, span)),
     }
 }
 
-fn parse_ops(tokens: &mut Lexer) -> Vec<Op> {
+/// Parse an operator list whose first name has already been consumed.
+fn parse_ops_from(mut ident: Span, tokens: &mut Lexer) -> Result<Vec<Op>, Diagnostic> {
     let mut ops = Vec::new();
-    while let Some((token, span)) = tokens.peek() {
-        match token {
-            Token::Identifier => {
-                tokens.next();
+    loop {
+        let then = match tokens.peek() {
+            Some((Token::Then, span)) => Some(span),
+            Some((Token::Newline, _)) | None => None,
+            Some((t, s)) => return Err(unexpected_op(t, s)),
+        };
+        ops.push(Op {
+            ident,
+            then: then.clone(),
+        });
 
-                let ident = span;
-                let then = match tokens.peek() {
-                    Some((Token::Then, span)) => Some(span),
-                    Some((Token::Newline, _)) | None => None,
-                    Some((t, s)) => tokens.reporter().report_and_exit(
-                        &Diagnostic::error()
-                            .with_message(&format!("unexpected token `{}` in operator list", t))
-                            .with_labels(
-                                vec![Label::primary((), s).with_message("not valid here")],
-                            ),
-                    ),
-                };
-                ops.push(Op {
-                    ident,
-                    then: then.clone(),
-                });
-
-                if then.is_none() {
-                    break;
-                }
+        if then.is_none() {
+            return Ok(ops);
+        }
+        tokens.next();
+        ident = match tokens.peek() {
+            Some((Token::Identifier, ident)) => {
                 tokens.next();
+                ident
             }
-            Token::Newline => break,
-            _ => tokens.reporter().report_and_exit(
-                &Diagnostic::error()
-                    .with_message(&format!("unexpected token `{}` in operator list", token))
-                    .with_labels(vec![Label::primary((), span).with_message("not valid here")]),
-            ),
-        }
+            Some((Token::Newline, _)) | None => return Ok(ops),
+            Some((t, s)) => return Err(unexpected_op(t, s)),
+        };
     }
-    ops
+}
+
+fn unexpected_op(token: Token, span: Span) -> Diagnostic {
+    Diagnostic::error()
+        .with_message(format!("unexpected token `{}` in operator list", token))
+        .with_labels(vec![Label::primary((), span).with_message("not valid here")])
 }
@@ -1,19 +1,51 @@
-use crate::ast1::{AssignTarget, AssignValue, Behaviour, Expr, Op, Parser1};
-use crate::error::{Diagnostic, Label, Reporter};
+use crate::ast1::{AssignTarget, AssignValue, Behaviour, Expr, Op, Parser1, Stmt};
+use crate::error::{Diagnostic, Label};
+use crate::prelude;
 use logos::Span;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct Identifier {
     pub id: usize,
-    //pub name: Span,
+    pub name: String,
+    /// Span of the name where it is declared, empty for `argc` and `argv`
+    pub decl: Span,
+}
+
+// Identifiers are told apart by id alone, the name and span are for reporting
+impl PartialEq for Identifier {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Identifier {}
+
+impl Hash for Identifier {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+// Ids count up as names are declared, so this is declaration order
+impl PartialOrd for Identifier {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Identifier {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.id.cmp(&other.id)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct CallExpr {
     pub function: Identifier,
-    pub p1: Box<DecoratedExpr>,
-    pub p2: Option<Box<DecoratedExpr>>,
+    pub args: Vec<DecoratedExpr>,
 }
 
 #[derive(Clone, Debug)]
@@ -47,8 +79,9 @@ pub struct LoadLiteralString {
 pub struct FuncDecl {
     pub line: usize,
     pub id: Identifier,
-    pub p1: Identifier,
-    pub p2: Option<Identifier>,
+    pub params: Vec<Identifier>,
+    /// `here for all`, exported under its name with external linkage
+    pub export: Option<Span>,
 }
 
 #[derive(Clone, Debug)]
@@ -66,11 +99,15 @@ pub struct Assignment {
 
 #[derive(Clone, Debug)]
 pub struct ExternFunction {
+    /// 0 for functions of the prelude that the file does not declare
     pub line: usize,
     pub name: String,
     pub ident: Identifier,
-    pub module: Option<String>,
-    pub two_param: bool,
+    /// Library named by `but is in`, and the span of its name
+    pub module: Option<(String, Span)>,
+    pub params: usize,
+    /// Declared `or more`, `params` is the minimum
+    pub variadic: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -82,7 +119,17 @@ pub struct ReturnStmt {
 #[derive(Clone, Debug)]
 pub struct GotoStmt {
     pub line: usize,
+    /// Span of the `goto` keyword
+    pub span: Span,
     pub target: DecoratedExpr,
+    /// Line the target always evaluates to, set by `goto::resolve`
+    pub dest: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LabelStmt {
+    pub line: usize,
+    pub ident: Identifier,
 }
 
 #[derive(Clone, Debug)]
@@ -100,19 +147,32 @@ pub enum DecoratedStmt {
     Assignment(Assignment),
     ReturnStmt(ReturnStmt),
     GotoStmt(GotoStmt),
+    Label(LabelStmt),
 }
 
 impl Callable {
-    pub fn two_params(&self) -> bool {
+    pub fn params(&self) -> usize {
+        match self {
+            Callable::FuncBlock(FuncBlock { decl, .. }) => decl.params.len(),
+            Callable::ExternFunction(ExternFunction { params, .. }) => *params,
+        }
+    }
+
+    /// Whether a call with `args` operands matches the parameters.
+    pub fn accepts(&self, args: usize) -> bool {
         match self {
-            Callable::FuncBlock(FuncBlock { decl, .. }) => decl.p2.is_some(),
-            Callable::ExternFunction(ExternFunction { two_param, .. }) => *two_param,
+            Callable::ExternFunction(ExternFunction {
+                variadic: true,
+                params,
+                ..
+            }) => args >= *params,
+            _ => args == self.params(),
         }
     }
 }
 
-pub const ARGC_IDENT: Identifier = Identifier { id: usize::MAX };
-pub const ARGV_IDENT: Identifier = Identifier { id: usize::MAX - 1 };
+pub const ARGC_ID: usize = usize::MAX;
+pub const ARGV_ID: usize = usize::MAX - 1;
 
 impl DecoratedStmt {
     pub fn line_number(&self) -> usize {
@@ -127,141 +187,497 @@ impl DecoratedStmt {
             DecoratedStmt::Assignment(stmt) => stmt.line,
             DecoratedStmt::ReturnStmt(stmt) => stmt.line,
             DecoratedStmt::GotoStmt(stmt) => stmt.line,
+            DecoratedStmt::Label(stmt) => stmt.line,
         }
     }
 }
 
-fn create_identifier<'a>(
-    ids: &mut HashMap<&'a str, Identifier>,
-    id: Option<&'a str>,
-    id_span: Option<Span>,
-    reporter: &Reporter<'a>,
-) -> Identifier {
-    let identifier = id.unwrap();
-    let span = id_span.unwrap();
-    if ids.contains_key(identifier) {
-        reporter.report_and_exit(
-            &Diagnostic::error()
-                .with_message("duplicate identifier")
-                .with_labels(vec![
-                    Label::primary((), span).with_message("this identifier is already declared")
-                ]),
-        );
+/// Look for any identifier that is not declared in this function and assume they are captures.
+pub fn find_captures(stmts: &[DecoratedStmt], params: &[usize]) -> Vec<Identifier> {
+    let mut locals = params.to_vec();
+    let mut captures = Vec::new();
+    fn process_expr(locals: &[usize], captures: &mut Vec<Identifier>, expr: &DecoratedExpr) {
+        match expr {
+            DecoratedExpr::Identifier(ident) => {
+                if !locals.contains(&ident.id) {
+                    captures.push(ident.clone());
+                }
+            }
+            DecoratedExpr::CallExpr(expr) => {
+                for arg in &expr.args {
+                    process_expr(locals, captures, arg);
+                }
+            }
+        }
+    }
+    fn process_stmt(locals: &mut Vec<usize>, captures: &mut Vec<Identifier>, stmt: &DecoratedStmt) {
+        match stmt {
+            DecoratedStmt::LoadLiteralNumber(stmt) => {
+                locals.push(stmt.ident.id);
+            }
+            DecoratedStmt::Callable(Callable::FuncBlock(block)) => {
+                locals.extend(block.decl.params.iter().map(|param| param.id));
+                for stmt in &block.block {
+                    process_stmt(locals, captures, stmt);
+                }
+            }
+            DecoratedStmt::Conditional(stmt) => {
+                if !locals.contains(&stmt.condition.id) {
+                    captures.push(stmt.condition.clone());
+                }
+                process_stmt(locals, captures, &stmt.success);
+            }
+            DecoratedStmt::Assignment(stmt) => {
+                process_expr(locals, captures, &stmt.value);
+                if let Some(ident) = &stmt.name {
+                    locals.push(ident.id);
+                }
+            }
+            DecoratedStmt::ReturnStmt(stmt) => {
+                process_expr(locals, captures, &stmt.expr);
+            }
+            _ => {}
+        }
+    }
+    for stmt in stmts {
+        process_stmt(&mut locals, &mut captures, stmt);
     }
-    let ident = Identifier { id: ids.len() };
-    ids.insert(identifier, ident);
-    ident
+    captures
 }
 
-fn create_or_shadow_ident_opt<'a>(
-    ids: &mut HashMap<&'a str, Identifier>,
-    id: Option<&'a str>,
-) -> Option<Identifier> {
-    let len = ids.len();
-    id.map(|id| *ids.entry(id).or_insert_with(|| Identifier { id: len }))
+/// Scope that holds `argc`, `argv` and every name declared outside of a function.
+const FILE_SCOPE: usize = 0;
+
+/// Spans of the label names in the file, by the name of the function they are in.
+type Labels<'a> = HashMap<Option<&'a str>, Vec<Span>>;
+
+/// Find every label up front, so a goto can jump to a label further down.
+fn collect_labels<'a>(src: &'a str, stmts: &[Result<Stmt, Diagnostic>]) -> Labels<'a> {
+    let mut labels = Labels::new();
+    for stmt in stmts.iter().flatten() {
+        let (function, name) = match &stmt.behaviour {
+            Behaviour::Label { name, .. } => (None, name),
+            Behaviour::StillIn {
+                ident, behaviour, ..
+            } => match &**behaviour {
+                Behaviour::Label { name, .. } => (Some(&src[ident.clone()]), name),
+                _ => continue,
+            },
+            _ => continue,
+        };
+        labels.entry(function).or_default().push(name.clone());
+    }
+    labels
 }
 
-fn create_or_shadow_ident<'a>(
-    ids: &mut HashMap<&'a str, Identifier>,
+#[derive(Default)]
+struct Scope<'a> {
+    parent: Option<usize>,
+    names: HashMap<&'a str, Identifier>,
+    /// Names used as values but declared in an enclosing scope, with their first use
+    captures: HashMap<&'a str, Span>,
+}
+
+/// The file scope and a scope for the body of every function, nested in the file scope.
+///
+/// Names resolve in the innermost scope that declares them. Values can be shadowed by an inner
+/// scope, functions can not, see docs/grammar.md. Labels are declared as soon as their scope is
+/// opened, the functions of the prelude before anything else in the file scope.
+struct Scopes<'a> {
     src: &'a str,
-    id: Span,
-) -> Identifier {
-    let len = ids.len();
-    *ids.entry(&src[id])
-        .or_insert_with(|| Identifier { id: len })
+    scopes: Vec<Scope<'a>>,
+    /// Function id -> scope of its body
+    bodies: HashMap<usize, usize>,
+    functions: HashSet<usize>,
+    /// Labels of scopes that are not open yet
+    pending: Labels<'a>,
+    labels: HashSet<usize>,
+    /// Functions of the prelude the file has not declared itself
+    prelude: HashSet<usize>,
+    next_id: usize,
+}
+
+impl<'a> Scopes<'a> {
+    fn new(src: &'a str, labels: Labels<'a>, prelude: &[prelude::Function]) -> Self {
+        let mut file = Scope::default();
+        for (name, id) in [("argc", ARGC_ID), ("argv", ARGV_ID)] {
+            let ident = Identifier {
+                id,
+                name: name.to_owned(),
+                decl: 0..0,
+            };
+            file.names.insert(name, ident);
+        }
+        let mut scopes = Scopes {
+            src,
+            scopes: vec![file],
+            bodies: HashMap::new(),
+            functions: HashSet::new(),
+            pending: labels,
+            labels: HashSet::new(),
+            prelude: HashSet::new(),
+            next_id: 0,
+        };
+        for function in prelude {
+            let ident = scopes.insert(FILE_SCOPE, function.name, 0..0);
+            scopes.functions.insert(ident.id);
+            scopes.prelude.insert(ident.id);
+        }
+        scopes.declare_labels(FILE_SCOPE, None);
+        scopes
+    }
+
+    /// Declare the labels of `function`, or of the file, in `scope`.
+    ///
+    /// Only the first label with a name is declared, the others are reported as duplicates
+    /// when they are parsed.
+    fn declare_labels(&mut self, scope: usize, function: Option<&'a str>) {
+        for span in self.pending.remove(&function).unwrap_or_default() {
+            let name = &self.src[span.clone()];
+            if !self.scopes[scope].names.contains_key(name) {
+                let ident = self.insert(scope, name, span);
+                self.labels.insert(ident.id);
+            }
+        }
+    }
+
+    fn find(&self, scope: usize, name: &str) -> Option<(usize, &Identifier)> {
+        let mut scope = Some(scope);
+        while let Some(i) = scope {
+            if let Some(ident) = self.scopes[i].names.get(name) {
+                return Some((i, ident));
+            }
+            scope = self.scopes[i].parent;
+        }
+        None
+    }
+
+    /// Look up a name that is called or jumped into rather than read.
+    fn get(&self, scope: usize, name: &str) -> Option<&Identifier> {
+        self.find(scope, name).map(|(_, ident)| ident)
+    }
+
+    /// Look up a name that is read, noting it as a capture if it comes from an enclosing scope.
+    fn value(&mut self, scope: usize, name: &'a str, span: Span) -> Option<Identifier> {
+        let (found, ident) = self.find(scope, name)?;
+        let ident = ident.clone();
+        if found != scope {
+            self.scopes[scope].captures.entry(name).or_insert(span);
+        }
+        Some(ident)
+    }
+
+    fn insert(&mut self, scope: usize, name: &'a str, span: Span) -> Identifier {
+        let ident = Identifier {
+            id: self.next_id,
+            name: name.to_owned(),
+            decl: span,
+        };
+        self.next_id += 1;
+        self.scopes[scope].names.insert(name, ident.clone());
+        ident
+    }
+
+    /// Declare a value in `scope`, or assign it again if `scope` already declares it.
+    fn declare_value(&mut self, scope: usize, span: Span) -> Result<Identifier, Diagnostic> {
+        let name = &self.src[span.clone()];
+        if let Some((found, ident)) = self.find(scope, name) {
+            if self.functions.contains(&ident.id) {
+                return Err(self.shadowed_function(ident, span));
+            }
+            if found == scope && self.labels.contains(&ident.id) {
+                return Err(declared_label(ident, span));
+            }
+            if found == scope {
+                return Ok(ident.clone());
+            }
+        }
+        if let Some(used) = self.scopes[scope].captures.get(name) {
+            return Err(Diagnostic::error()
+                .with_message("ambiguous capture")
+                .with_labels(vec![
+                    Label::primary((), span)
+                        .with_message("this assignment declares a new local variable"),
+                    Label::secondary((), used.clone())
+                        .with_message("but the same name is captured from outside the function here"),
+                ])
+                .with_notes(vec![format!(
+                    "`{}` would refer to two different variables in the same function, give the local variable another name",
+                    name
+                )]));
+        }
+        Ok(self.insert(scope, name, span))
+    }
+
+    fn declare_value_opt(
+        &mut self,
+        scope: usize,
+        span: Option<Span>,
+    ) -> Result<Option<Identifier>, Diagnostic> {
+        span.map(|span| self.declare_value(scope, span)).transpose()
+    }
+
+    /// Declare a function in `scope`, it may shadow a value but not another function.
+    fn declare_function(&mut self, scope: usize, span: Span) -> Result<Identifier, Diagnostic> {
+        let name = &self.src[span.clone()];
+        if let Some((found, ident)) = self.find(scope, name) {
+            if self.functions.contains(&ident.id) {
+                return Err(self.shadowed_function(ident, span));
+            }
+            if found == scope && self.labels.contains(&ident.id) {
+                return Err(declared_label(ident, span));
+            }
+        }
+        let ident = self.insert(scope, name, span);
+        self.functions.insert(ident.id);
+        Ok(ident)
+    }
+
+    /// Declare an external function in `scope`, like `declare_function`, except that the file
+    /// may declare a function of the prelude again if it takes the same parameters.
+    fn declare_extern(
+        &mut self,
+        scope: usize,
+        span: Span,
+        params: usize,
+        variadic: bool,
+    ) -> Result<Identifier, Diagnostic> {
+        let name = &self.src[span.clone()];
+        let redeclared = match self.scopes[scope].names.get(name) {
+            Some(ident) if self.prelude.contains(&ident.id) => prelude::get(name),
+            _ => None,
+        };
+        let function = match redeclared {
+            Some(function) => function,
+            None => return self.declare_function(scope, span),
+        };
+        if function.params != params || variadic {
+            return Err(Diagnostic::error()
+                .with_message("prelude function declared with different parameters")
+                .with_labels(vec![Label::primary((), span).with_message(format!(
+                    "`{}` of the standard library takes {} {}",
+                    name,
+                    function.params,
+                    if function.params == 1 {
+                        "parameter"
+                    } else {
+                        "parameters"
+                    }
+                ))])
+                .with_notes(vec![
+                    "pass `--no-std` to declare your own function with this name".to_string(),
+                ]));
+        }
+        let ident = self.scopes[scope].names.get_mut(name).unwrap();
+        ident.decl = span;
+        let ident = ident.clone();
+        self.prelude.remove(&ident.id);
+        Ok(ident)
+    }
+
+    fn shadowed_function(&self, function: &Identifier, span: Span) -> Diagnostic {
+        if !self.prelude.contains(&function.id) {
+            return shadowed_function(function, span);
+        }
+        Diagnostic::error()
+            .with_message("function shadowed")
+            .with_labels(vec![Label::primary((), span).with_message(
+                "this name is already declared as a function of the prelude",
+            )])
+            .with_notes(vec![format!(
+                "`{}` is part of the standard library, give this another name or pass `--no-std`",
+                function.name
+            )])
+    }
+
+    /// Open the scope of the body of `function`, nested in the scope it is declared in.
+    fn open_body(&mut self, function: &Identifier, parent: usize) -> usize {
+        self.scopes.push(Scope {
+            parent: Some(parent),
+            ..Scope::default()
+        });
+        let body = self.scopes.len() - 1;
+        self.bodies.insert(function.id, body);
+        self.declare_labels(body, Some(&self.src[function.decl.clone()]));
+        body
+    }
+
+    fn body(&self, function: &Identifier) -> Option<usize> {
+        self.bodies.get(&function.id).copied()
+    }
+}
+
+fn shadowed_function(function: &Identifier, span: Span) -> Diagnostic {
+    Diagnostic::error()
+        .with_message("function shadowed")
+        .with_labels(vec![
+            Label::primary((), span).with_message("this name is already declared as a function"),
+            Label::secondary((), function.decl.clone()).with_message("function declared here"),
+        ])
+        .with_notes(vec![
+            "functions, including external functions, cannot be shadowed".to_string(),
+        ])
+}
+
+fn declared_label(label: &Identifier, span: Span) -> Diagnostic {
+    Diagnostic::error()
+        .with_message("name already used by a label")
+        .with_labels(vec![
+            Label::primary((), span).with_message("this name is already declared as a label"),
+            Label::secondary((), label.decl.clone()).with_message("label declared here"),
+        ])
+        .with_notes(vec![
+            "a label can not be assigned, it always names the line it is on".to_string(),
+        ])
+}
+
+fn label_used_as_value(label: &Identifier, span: Span) -> Diagnostic {
+    Diagnostic::error()
+        .with_message("label used as value")
+        .with_labels(vec![
+            Label::primary((), span).with_message("this is a label and not a variable"),
+            Label::secondary((), label.decl.clone()).with_message("label declared here"),
+        ])
+        .with_notes(vec![
+            "labels can only be used as the whole target of a goto".to_string(),
+        ])
 }
 
 fn parse_behaviour<'a>(
     line: usize,
     behaviour: Behaviour,
     expr: Option<Expr>,
-    ids: &mut HashMap<&'a str, Identifier>,
-    func_ids: &mut HashMap<Identifier, Callable>,
-    parser: &Parser1<'a>,
-) -> Option<DecoratedStmt> {
-    let src = parser.src();
+    scopes: &mut Scopes<'a>,
+    scope: usize,
+    func_ids: &mut BTreeMap<Identifier, Callable>,
+    errors: &mut Vec<Diagnostic>,
+) -> Result<Option<DecoratedStmt>, Diagnostic> {
+    let src = scopes.src;
     match behaviour {
         Behaviour::Assign {
             target: target @ (AssignTarget::Ident(_) | AssignTarget::Discard(_)),
+            is,
             value,
-            ..
         } => {
-            let (id, id_span) = match target {
-                AssignTarget::Ident(span) => (Some(&src[span.clone()]), Some(span)),
-                _ => (None, None),
+            let (id_span, target_span) = match target {
+                AssignTarget::Ident(span) => (Some(span.clone()), span),
+                AssignTarget::Discard(span) => (None, span),
+                _ => unreachable!(),
+            };
+            let span = target_span.start..is.end;
+            // Literals and declarations are only reachable through their name
+            let name = |what: &str| {
+                id_span.clone().ok_or_else(|| {
+                    Diagnostic::error()
+                        .with_message(format!("{} needs a name", what))
+                        .with_labels(vec![Label::primary((), span.clone())
+                            .with_message("`yeet` discards the value")])
+                })
             };
             match value {
                 AssignValue::Number(_, n) => {
-                    // Must have an identifier for loading literals
-                    Some(DecoratedStmt::LoadLiteralNumber(LoadLiteralNumber {
+                    Ok(Some(DecoratedStmt::LoadLiteralNumber(LoadLiteralNumber {
                         line,
-                        ident: create_or_shadow_ident(ids, src, id_span.unwrap()),
+                        ident: scopes.declare_value(scope, name("a literal")?)?,
                         value: n,
-                    }))
+                    })))
                 }
                 AssignValue::String(str_span) => {
-                    // Must have an identifier for loading literals
-                    Some(DecoratedStmt::LoadLiteralString(LoadLiteralString {
+                    let value = unescape::unescape(&src[(str_span.start + 1)..(str_span.end - 1)])
+                        .ok_or_else(|| {
+                            Diagnostic::error()
+                                .with_message("invalid string literal")
+                                .with_labels(vec![Label::primary((), str_span).with_message(
+                                    "this literal contains illegal escape sequences",
+                                )])
+                        })?;
+                    Ok(Some(DecoratedStmt::LoadLiteralString(LoadLiteralString {
                         line,
-                        ident: create_or_shadow_ident(ids, src, id_span.unwrap()),
-                        value: unescape::unescape(&src[(str_span.start + 1)..(str_span.end - 1)])
-                            .unwrap_or_else(|| {
-                                parser.reporter().report_and_exit(
-                                    &Diagnostic::error()
-                                        .with_message("invalid string literal")
-                                        .with_labels(vec![Label::primary((), str_span)
-                                            .with_message(
-                                                "this literal contains illegal escape sequences",
-                                            )]),
-                                )
-                            }),
-                    }))
+                        ident: scopes.declare_value(scope, name("a literal")?)?,
+                        value,
+                    })))
                 }
                 AssignValue::NotHere(not_here) => {
-                    // Must have an identifier for exported functions
-                    let ident = create_identifier(ids, id, id_span, &parser.reporter());
+                    let id_span = name("an external function")?;
+                    if let Some((span, n @ (0 | 1))) = &not_here.params {
+                        return Err(Diagnostic::error()
+                            .with_message("invalid parameter count")
+                            .with_labels(vec![Label::primary((), span.clone()).with_message(
+                                format!("a big function takes at least two parameters, not {}", n),
+                            )]));
+                    }
+                    let ident = scopes.declare_extern(
+                        scope,
+                        id_span,
+                        not_here.param_count(),
+                        not_here.or_more.is_some(),
+                    )?;
                     func_ids.insert(
-                        ident,
+                        ident.clone(),
                         Callable::ExternFunction(ExternFunction {
                             line,
-                            name: id.unwrap().to_owned(),
+                            name: ident.name.clone(),
                             ident,
-                            module: not_here.ident.map(|value| src[value].to_owned()),
-                            two_param: not_here.and_is_big.is_some(),
+                            module: not_here
+                                .module(src)
+                                .zip(not_here.ident.clone())
+                                .map(|(module, span)| (module.to_owned(), span)),
+                            params: not_here.param_count(),
+                            variadic: not_here.or_more.is_some(),
                         }),
                     );
-                    None
+                    Ok(None)
                 }
                 AssignValue::Ops(ops) => {
                     // Made up of CallExprs
                     // Each op needs to match the expression op
-                    let ident = create_or_shadow_ident_opt(ids, id);
-
-                    Some(DecoratedStmt::Assignment(Assignment {
+                    // The value is resolved before the name is declared, `a. // a is inc` reads
+                    // the `a` of the enclosing scope
+                    let expr = expr.ok_or_else(|| {
+                        Diagnostic::error()
+                            .with_message("operation without an expression")
+                            .with_labels(vec![Label::primary((), span.clone())
+                                .with_message("this assignment has no expression before `//`")])
+                    })?;
+                    let value = zip_ops_with_expr(&expr, &ops, scopes, scope, func_ids, errors);
+                    Ok(Some(DecoratedStmt::Assignment(Assignment {
                         line,
-                        name: ident,
-                        value: zip_ops_with_expr(
-                            &expr.unwrap(),
-                            &ops,
-                            ids,
-                            func_ids,
-                            src,
-                            &parser.reporter(),
-                        ),
-                    }))
+                        name: scopes.declare_value_opt(scope, id_span)?,
+                        value,
+                    })))
                 }
                 AssignValue::Fn(f) => {
                     // Create a function declaration for this, make a function definition for this, add to function collection
                     // All functions will be added to the output vector before being returned
-                    let ident = create_identifier(ids, id, id_span, &parser.reporter());
-                    let p1 = create_or_shadow_ident(ids, src, f.params.p1);
-                    let p2 = create_or_shadow_ident_opt(ids, f.params.p2.map(|v| &src[v]));
+                    let ident = scopes.declare_function(scope, name("a function")?)?;
+                    let body = scopes.open_body(&ident, scope);
+                    let names = &f.params.names;
+                    for (i, name) in names.iter().enumerate() {
+                        let first = names[..i]
+                            .iter()
+                            .find(|first| src[(*first).clone()] == src[name.clone()]);
+                        if let Some(first) = first {
+                            return Err(Diagnostic::error()
+                                .with_message("duplicate parameter")
+                                .with_labels(vec![
+                                    Label::primary((), name.clone()).with_message(
+                                        "this parameter has the same name as an earlier one",
+                                    ),
+                                    Label::secondary((), first.clone())
+                                        .with_message("first parameter declared here"),
+                                ]));
+                        }
+                    }
+                    let params = names
+                        .iter()
+                        .map(|name| scopes.declare_value(body, name.clone()))
+                        .collect::<Result<_, _>>()?;
                     let mut block = FuncBlock {
                         decl: FuncDecl {
                             line,
-                            id: ident,
-                            p1,
-                            p2,
+                            id: ident.clone(),
+                            params,
+                            export: f.export.clone(),
                         },
                         block: Vec::new(),
                     };
@@ -269,20 +685,13 @@ fn parse_behaviour<'a>(
                         block.block.push(DecoratedStmt::Assignment(Assignment {
                             line,
                             name: None,
-                            value: zip_ops_with_expr(
-                                expr,
-                                &f.ops,
-                                ids,
-                                func_ids,
-                                src,
-                                &parser.reporter(),
-                            ),
+                            value: zip_ops_with_expr(expr, &f.ops, scopes, body, func_ids, errors),
                         }));
                     }
                     func_ids.insert(ident, Callable::FuncBlock(block));
                     // This should NOT be added right away, since it will not hold all of the potential blocks.
                     // See func_ids instead.
-                    None
+                    Ok(None)
                 }
             }
         }
@@ -291,33 +700,44 @@ fn parse_behaviour<'a>(
             value,
             ..
         } => {
-            if expr.is_none() {
-                parser.reporter().report_and_exit(
-                    &Diagnostic::error()
-                        .with_message("goto without expression")
-                        .with_labels(vec![Label::primary((), span)
-                            .with_message("this goto is missing a target expression")]),
-                );
-            }
+            let expr = expr.ok_or_else(|| {
+                Diagnostic::error()
+                    .with_message("goto without expression")
+                    .with_labels(vec![Label::primary((), span.clone())
+                        .with_message("this goto is missing a target expression")])
+            })?;
             match value {
-                AssignValue::Ops(ops) => Some(DecoratedStmt::GotoStmt(GotoStmt {
-                    line,
-                    target: zip_ops_with_expr(
-                        &expr.unwrap(),
-                        &ops,
-                        ids,
-                        func_ids,
-                        src,
-                        &parser.reporter(),
-                    ),
-                })),
-                _ => parser.reporter().report_and_exit(
-                    &Diagnostic::error()
-                        .with_message("goto with invalid assignment")
-                        .with_labels(vec![Label::primary((), span).with_message(
-                            "this goto is assigned something other than an expression",
-                        )]),
-                ),
+                AssignValue::Ops(ops) => {
+                    // A lone name is a label, or a variable holding a line number
+                    let label = match (&expr, &ops[..]) {
+                        (Expr::Ident(name), []) => {
+                            let ident = scopes.get(scope, &src[name.clone()]).cloned();
+                            let ident = ident.ok_or_else(|| {
+                                Diagnostic::error()
+                                    .with_message("unknown label")
+                                    .with_labels(vec![Label::primary((), name.clone())
+                                        .with_message("no label or variable has this name")])
+                            })?;
+                            Some(ident).filter(|ident| scopes.labels.contains(&ident.id))
+                        }
+                        _ => None,
+                    };
+                    let target = match label {
+                        Some(label) => DecoratedExpr::Identifier(label),
+                        None => zip_ops_with_expr(&expr, &ops, scopes, scope, func_ids, errors),
+                    };
+                    Ok(Some(DecoratedStmt::GotoStmt(GotoStmt {
+                        line,
+                        span,
+                        target,
+                        dest: None,
+                    })))
+                }
+                _ => Err(Diagnostic::error()
+                    .with_message("goto with invalid assignment")
+                    .with_labels(vec![Label::primary((), span).with_message(
+                        "this goto is assigned something other than an expression",
+                    )])),
             }
         }
         Behaviour::Assign {
@@ -325,297 +745,475 @@ fn parse_behaviour<'a>(
             value,
             ..
         } => {
-            if expr.is_none() {
-                parser.reporter().report_and_exit(
-                    &Diagnostic::error()
-                        .with_message("return without expression")
-                        .with_labels(vec![Label::primary((), span)
-                            .with_message("this return is missing a value expression")]),
-                );
-            }
+            let expr = expr.ok_or_else(|| {
+                Diagnostic::error()
+                    .with_message("return without expression")
+                    .with_labels(vec![Label::primary((), span.clone())
+                        .with_message("this return is missing a value expression")])
+            })?;
             match value {
-                AssignValue::Ops(ops) => Some(DecoratedStmt::ReturnStmt(ReturnStmt {
+                AssignValue::Ops(ops) => Ok(Some(DecoratedStmt::ReturnStmt(ReturnStmt {
                     line,
-                    expr: zip_ops_with_expr(
-                        &expr.unwrap(),
-                        &ops,
-                        ids,
-                        func_ids,
-                        src,
-                        &parser.reporter(),
-                    ),
-                })),
-                _ => parser.reporter().report_and_exit(
-                    &Diagnostic::error()
-                        .with_message("return with invalid assignment")
-                        .with_labels(vec![Label::primary((), span).with_message(
-                            "this return is assigned something other than an expression",
-                        )]),
-                ),
+                    expr: zip_ops_with_expr(&expr, &ops, scopes, scope, func_ids, errors),
+                }))),
+                _ => Err(Diagnostic::error()
+                    .with_message("return with invalid assignment")
+                    .with_labels(vec![Label::primary((), span).with_message(
+                        "this return is assigned something other than an expression",
+                    )])),
+            }
+        }
+        Behaviour::Label { name, .. } => {
+            if let Some(expr) = expr {
+                return Err(Diagnostic::error()
+                    .with_message("label with expression")
+                    .with_labels(vec![Label::primary((), expr.span())
+                        .with_message("a label does not take an expression")]));
+            }
+            // Declared along with the scope, see `Scopes::declare_labels`
+            match scopes.scopes[scope].names.get(&src[name.clone()]) {
+                Some(ident) if ident.decl == name => Ok(Some(DecoratedStmt::Label(LabelStmt {
+                    line,
+                    ident: ident.clone(),
+                }))),
+                Some(ident) if scopes.prelude.contains(&ident.id) => {
+                    Err(scopes.shadowed_function(ident, name))
+                }
+                Some(ident) if scopes.labels.contains(&ident.id) => Err(Diagnostic::error()
+                    .with_message("duplicate label")
+                    .with_labels(vec![
+                        Label::primary((), name)
+                            .with_message("a label with this name is already declared"),
+                        Label::secondary((), ident.decl.clone())
+                            .with_message("first declared here"),
+                    ])),
+                _ => Err(Diagnostic::error()
+                    .with_message("label shadows argument")
+                    .with_labels(vec![Label::primary((), name)
+                        .with_message("`argc` and `argv` can not be used as labels")])),
             }
         }
         Behaviour::StillIn {
-            ident, behaviour, ..
+            still_in,
+            ident,
+            behaviour,
         } => {
-            // Recursive parse behaviour
-            // Map ident to correct function
+            // Recursive parse behaviour in the scope of the function body
             let ident_str = &src[ident.clone()];
-            let func = ids.get(ident_str).cloned();
-            if func.is_none() {
-                parser.reporter().report_and_exit(
-                    &Diagnostic::error()
-                        .with_message("unbound function body")
+            let func = scopes.get(scope, ident_str).cloned().ok_or_else(|| {
+                Diagnostic::error()
+                    .with_message("unbound function body")
+                    .with_labels(vec![Label::primary((), ident.clone())
+                        .with_message("this function is not declared")])
+            })?;
+            let body_scope = match func_ids.get(&func) {
+                Some(Callable::FuncBlock(_)) => scopes.body(&func).unwrap(),
+                Some(Callable::ExternFunction(_)) => {
+                    return Err(Diagnostic::error()
+                        .with_message("function body bound to external function")
                         .with_labels(vec![
-                            Label::primary((), ident).with_message("this function is not declared")
-                        ]),
-                );
-            }
-            let ret = parse_behaviour(line, *behaviour, expr, ids, func_ids, parser).unwrap();
-            let body = func_ids.get_mut(&func.unwrap());
-            if body.is_none() {
-                parser.reporter().report_and_exit(
-                    &Diagnostic::error()
+                            Label::primary((), ident).with_message("this function is external")
+                        ]))
+                }
+                None => {
+                    return Err(Diagnostic::error()
                         .with_message("function body bound to variable")
-                        .with_labels(vec![Label::primary((), ident)
-                            .with_message("this is a variable and not a function")]),
-                );
-            }
-            match body.unwrap() {
-                Callable::FuncBlock(FuncBlock { block, .. }) => block.push(ret),
-                Callable::ExternFunction(ExternFunction { .. }) => {
-                    parser.reporter().report_and_exit(
-                        &Diagnostic::error()
-                            .with_message("function body bound to external function")
-                            .with_labels(vec![
-                                Label::primary((), ident).with_message("this function is external")
-                            ]),
-                    )
+                        .with_labels(vec![Label::primary((), ident.clone())
+                            .with_message("this is a variable and not a function")]))
                 }
+            };
+            let ret =
+                parse_behaviour(line, *behaviour, expr, scopes, body_scope, func_ids, errors)?
+                    .ok_or_else(|| {
+                        Diagnostic::error()
+                            .with_message("nested function declaration")
+                            .with_labels(vec![Label::primary((), (still_in.start)..(ident.end))
+                                .with_message("function body here")])
+                            .with_notes(vec![
+                                "functions cannot be declared inside functions".to_string()
+                            ])
+                    })?;
+            if let Some(Callable::FuncBlock(FuncBlock { block, .. })) = func_ids.get_mut(&func) {
+                block.push(ret);
             }
-            None
+            Ok(None)
         }
         Behaviour::Cond {
             cond,
             behaviour,
             if_,
         } => {
+            if let Behaviour::Label { .. } = *behaviour {
+                return Err(Diagnostic::error()
+                    .with_message("conditional label")
+                    .with_labels(vec![Label::primary((), (if_.start)..(cond.end))
+                        .with_message("conditional statement here")])
+                    .with_notes(vec![
+                        "a label always names its line, put the condition on the goto instead"
+                            .to_string(),
+                    ]));
+            }
             // Recursive parse behaviour
             // Map cond to identifier
             let ident_str = &src[cond.clone()];
-            let ident = ids.get(ident_str).unwrap_or_else(|| {
-                parser.reporter().report_and_exit(
-                    &Diagnostic::error()
+            let ident = scopes
+                .value(scope, ident_str, cond.clone())
+                .ok_or_else(|| {
+                    Diagnostic::error()
                         .with_message("unbound condition")
                         .with_labels(vec![Label::primary((), cond.clone())
-                            .with_message("this variable is not defined")]),
-                );
-            });
-            Some(DecoratedStmt::Conditional(Conditional {
-                condition: *ident,
-                success: Box::new(
-                    parse_behaviour(line, *behaviour, expr, ids, func_ids, parser).unwrap_or_else(
-                        || {
-                            parser.reporter().report_and_exit(
-                                &Diagnostic::error()
-                                    .with_message("conditional function declaration")
-                                    .with_labels(vec![Label::primary((), (if_.start)..(cond.end))
-                                        .with_message("conditional statement here")])
-                                    .with_notes(vec![
-                                        "functions cannot be conditionally declared".to_string()
-                                    ]),
-                            )
-                        },
-                    ),
-                ),
-            }))
+                            .with_message("this variable is not defined")])
+                })?;
+            if scopes.labels.contains(&ident.id) {
+                return Err(label_used_as_value(&ident, cond));
+            }
+            let success = parse_behaviour(line, *behaviour, expr, scopes, scope, func_ids, errors)?
+                .ok_or_else(|| {
+                    Diagnostic::error()
+                        .with_message("conditional function declaration")
+                        .with_labels(vec![Label::primary((), (if_.start)..(cond.end))
+                            .with_message("conditional statement here")])
+                        .with_notes(vec![
+                            "functions cannot be conditionally declared".to_string()
+                        ])
+                })?;
+            Ok(Some(DecoratedStmt::Conditional(Conditional {
+                condition: ident,
+                success: Box::new(success),
+            })))
         }
     }
 }
 
-pub fn parse(mut parser: Parser1) -> Vec<DecoratedStmt> {
+/// Resolve the statements of a program.
+///
+/// Statements that fail to parse or resolve are skipped so that every error in the file is
+/// collected. Once `error_limit` errors have been found parsing stops, a limit of 0 means
+/// there is no limit.
+///
+/// The functions of `prelude` can be called without being declared, the result declares the
+/// ones that are used.
+pub fn parse(
+    parser: Parser1,
+    error_limit: usize,
+    prelude: &[prelude::Function],
+) -> Result<Vec<DecoratedStmt>, Vec<Diagnostic>> {
     let mut outp = Vec::new();
-    let mut ids = HashMap::new();
-    ids.insert("argc", ARGC_IDENT);
-    ids.insert("argv", ARGV_IDENT);
-
-    let mut func_ids = HashMap::new();
-    while let Some(stmt) = parser.next() {
-        let val = parse_behaviour(
-            stmt.line,
-            stmt.behaviour,
-            stmt.expr,
-            &mut ids,
-            &mut func_ids,
-            &parser,
+    let src = parser.src();
+    let stmts: Vec<_> = parser.collect();
+    let mut scopes = Scopes::new(src, collect_labels(src, &stmts), prelude);
+
+    // Ordered so functions come out in the order they are declared, whatever the hasher
+    let mut func_ids = BTreeMap::new();
+    for function in prelude {
+        let ident = scopes.get(FILE_SCOPE, function.name).unwrap().clone();
+        func_ids.insert(
+            ident.clone(),
+            Callable::ExternFunction(ExternFunction {
+                line: 0,
+                name: ident.name.clone(),
+                ident,
+                module: None,
+                params: function.params,
+                variadic: false,
+            }),
         );
-        if let Some(val) = val {
-            outp.push(val);
+    }
+    let mut errors = Vec::new();
+    for stmt in stmts {
+        let val = stmt.and_then(|stmt| {
+            parse_behaviour(
+                stmt.line,
+                stmt.behaviour,
+                stmt.expr,
+                &mut scopes,
+                FILE_SCOPE,
+                &mut func_ids,
+                &mut errors,
+            )
+        });
+        match val {
+            Ok(Some(val)) => outp.push(val),
+            Ok(None) => {}
+            Err(diagnostic) => errors.push(diagnostic),
         }
+        if error_limit != 0 && errors.len() >= error_limit {
+            errors.truncate(error_limit);
+            errors.push(Diagnostic::note().with_message(format!(
+                "aborting after {} errors, the rest of the file was not checked",
+                error_limit
+            )));
+            break;
+        }
+    }
+    errors.extend(func_ids.values().filter_map(check_export));
+    if !errors.is_empty() {
+        return Err(errors);
     }
+    // Only declare the functions of the prelude that are used
+    let used = used_functions(&outp, &func_ids);
+    func_ids.retain(|ident, _| !scopes.prelude.contains(&ident.id) || used.contains(&ident.id));
     outp.extend(func_ids.into_values().map(DecoratedStmt::Callable));
-    outp
+    Ok(outp)
+}
+
+/// An exported function is called from C with its parameters only, so it can't capture.
+fn check_export(function: &Callable) -> Option<Diagnostic> {
+    let block = match function {
+        Callable::FuncBlock(block) => block,
+        Callable::ExternFunction(_) => return None,
+    };
+    let export = block.decl.export.clone()?;
+    let params: Vec<_> = block.decl.params.iter().map(|param| param.id).collect();
+    let capture = find_captures(&block.block, &params).into_iter().next()?;
+    let mut labels = vec![Label::primary((), export).with_message(format!(
+        "{} is exported here, but it captures `{}`",
+        block.decl.id.name, capture.name
+    ))];
+    if !capture.decl.is_empty() {
+        labels.push(
+            Label::secondary((), capture.decl).with_message("declared outside of the function"),
+        );
+    }
+    Some(
+        Diagnostic::error()
+            .with_message("exported function captures a value")
+            .with_labels(labels)
+            .with_notes(vec![
+                "pass the value as a parameter instead, C code only passes the parameters"
+                    .to_string(),
+            ]),
+    )
+}
+
+/// Ids of every function called or read by `stmts` and the bodies of `functions`.
+fn used_functions(
+    stmts: &[DecoratedStmt],
+    functions: &BTreeMap<Identifier, Callable>,
+) -> HashSet<usize> {
+    fn process_expr(used: &mut HashSet<usize>, expr: &DecoratedExpr) {
+        match expr {
+            DecoratedExpr::CallExpr(call) => {
+                used.insert(call.function.id);
+                for arg in &call.args {
+                    process_expr(used, arg);
+                }
+            }
+            DecoratedExpr::Identifier(ident) => {
+                used.insert(ident.id);
+            }
+        }
+    }
+    fn process_stmt(used: &mut HashSet<usize>, stmt: &DecoratedStmt) {
+        match stmt {
+            DecoratedStmt::Conditional(stmt) => process_stmt(used, &stmt.success),
+            DecoratedStmt::Assignment(Assignment { value: expr, .. })
+            | DecoratedStmt::ReturnStmt(ReturnStmt { expr, .. })
+            | DecoratedStmt::GotoStmt(GotoStmt { target: expr, .. }) => process_expr(used, expr),
+            _ => {}
+        }
+    }
+    let mut used = HashSet::new();
+    let bodies = functions.values().filter_map(|function| match function {
+        Callable::FuncBlock(block) => Some(block.block.as_slice()),
+        Callable::ExternFunction(_) => None,
+    });
+    for stmt in std::iter::once(stmts).chain(bodies).flatten() {
+        process_stmt(&mut used, stmt);
+    }
+    used
 }
 
+/// Bind the operations of a statement to the dots of its expression.
+///
+/// Errors are pushed to `errors` and binding carries on, so every unbound name and wrong
+/// parameter count in the expression is reported at once. The returned expression is only
+/// meaningful if no errors were pushed.
 fn zip_ops_with_expr<'a>(
     expr: &Expr,
     ops: &[Op],
-    ids: &HashMap<&'a str, Identifier>,
-    fn_ids: &HashMap<Identifier, Callable>,
-    src: &'a str,
-    reporter: &Reporter<'a>,
+    scopes: &mut Scopes<'a>,
+    scope: usize,
+    fn_ids: &BTreeMap<Identifier, Callable>,
+    errors: &mut Vec<Diagnostic>,
 ) -> DecoratedExpr {
+    fn bind_op<'a, 'ops, 'f>(
+        dot: &Span,
+        ops: &'ops [Op],
+        scopes: &Scopes<'a>,
+        scope: usize,
+        fn_ids: &'f BTreeMap<Identifier, Callable>,
+    ) -> Result<(&'ops Op, Identifier, &'f Callable), Diagnostic> {
+        let src = scopes.src;
+        let op = ops.first().ok_or_else(|| {
+            Diagnostic::error()
+                .with_message("unbound operation")
+                .with_labels(vec![Label::primary((), dot.clone())
+                    .with_message("this operation is not bound to any function")])
+        })?;
+
+        let ident = scopes
+            .get(scope, &src[op.ident.clone()])
+            .cloned()
+            .ok_or_else(|| {
+                Diagnostic::error()
+                    .with_message("unbound operation")
+                    .with_labels(vec![
+                        Label::primary((), op.ident.clone())
+                            .with_message("this function is not defined"),
+                        Label::secondary((), dot.clone()).with_message("for this operation"),
+                    ])
+            })?;
+        let fun = fn_ids.get(&ident).ok_or_else(|| {
+            Diagnostic::error()
+                .with_message("operation bound to variable")
+                .with_labels(vec![
+                    Label::primary((), op.ident.clone())
+                        .with_message("this is a variable and not a function"),
+                    Label::secondary((), dot.clone()).with_message("for this operation"),
+                ])
+        })?;
+        Ok((op, ident, fun))
+    }
+
+    /// Decorate the operands of a call in order, then bind the operation of its dot.
+    fn call<'a, 'ops>(
+        dot: &Span,
+        operands: &[&Expr],
+        mut ops: &'ops [Op],
+        scopes: &mut Scopes<'a>,
+        scope: usize,
+        fn_ids: &BTreeMap<Identifier, Callable>,
+        errors: &mut Vec<Diagnostic>,
+    ) -> (DecoratedExpr, &'ops [Op]) {
+        let mut args = Vec::new();
+        for operand in operands {
+            let (arg, rest) = inner(operand, ops, scopes, scope, fn_ids, errors);
+            args.push(arg);
+            ops = rest;
+        }
+        let (op, ident, fun) = match bind_op(dot, ops, scopes, scope, fn_ids) {
+            Ok(bound) => bound,
+            Err(diagnostic) => {
+                errors.push(diagnostic);
+                return (args.swap_remove(0), ops.get(1..).unwrap_or_default());
+            }
+        };
+        if !fun.accepts(args.len()) {
+            let takes = match (
+                fun.params(),
+                matches!(fun, Callable::ExternFunction(f) if f.variadic),
+            ) {
+                (1, false) => "this function only takes one parameter".to_owned(),
+                (n, false) => format!("this function takes {} parameters", n),
+                (1, true) => "this function takes at least one parameter".to_owned(),
+                (n, true) => format!("this function takes at least {} parameters", n),
+            };
+            let mut labels = vec![Label::primary((), op.ident.clone()).with_message(takes)];
+            labels.extend(operands.iter().enumerate().map(|(i, operand)| {
+                let provided = match operands.len() {
+                    1 => "single parameter provided here".to_owned(),
+                    _ => format!("parameter {} provided here", i + 1),
+                };
+                Label::secondary((), operand.span()).with_message(provided)
+            }));
+            errors.push(
+                Diagnostic::error()
+                    .with_message("invalid parameter count")
+                    .with_labels(labels),
+            );
+        }
+        (
+            DecoratedExpr::CallExpr(CallExpr {
+                function: ident,
+                args,
+            }),
+            &ops[1..],
+        )
+    }
+
     fn inner<'a, 'ops>(
         expr: &Expr,
         ops: &'ops [Op],
-        ids: &HashMap<&'a str, Identifier>,
-        fn_ids: &HashMap<Identifier, Callable>,
-        src: &'a str,
-        reporter: &Reporter<'a>,
+        scopes: &mut Scopes<'a>,
+        scope: usize,
+        fn_ids: &BTreeMap<Identifier, Callable>,
+        errors: &mut Vec<Diagnostic>,
     ) -> (DecoratedExpr, &'ops [Op]) {
+        let src = scopes.src;
         match expr {
-            Expr::Binop {
-                lhs: lhs_expr,
-                rhs: rhs_expr,
-                op: dot,
-            } => {
-                let (lhs, ops) = inner(lhs_expr, ops, ids, fn_ids, src, reporter);
-                let (rhs, ops) = inner(rhs_expr, ops, ids, fn_ids, src, reporter);
-                let (op, ops) = ops.split_first().unwrap_or_else(|| {
-                    reporter.report_and_exit(
-                        &Diagnostic::error()
-                            .with_message("unbound operation")
-                            .with_labels(vec![Label::primary((), dot.clone())
-                                .with_message("this operation is not bound to any function")]),
-                    )
-                });
-
-                let ident = ids.get(&src[op.ident.clone()]).unwrap_or_else(|| {
-                    reporter.report_and_exit(
-                        &Diagnostic::error()
-                            .with_message("unbound operation")
-                            .with_labels(vec![
-                                Label::primary((), op.ident.clone())
-                                    .with_message("this function is not defined"),
-                                Label::secondary((), dot.clone())
-                                    .with_message("for this operation"),
-                            ]),
-                    )
-                });
-                let fun = fn_ids.get(ident).unwrap_or_else(|| {
-                    reporter.report_and_exit(
-                        &Diagnostic::error()
-                            .with_message("operation bound to variable")
-                            .with_labels(vec![
-                                Label::primary((), op.ident.clone())
-                                    .with_message("this is a variable and not a function"),
-                                Label::secondary((), dot.clone())
-                                    .with_message("for this operation"),
-                            ]),
-                    )
-                });
-                if !fun.two_params() {
-                    reporter.report_and_exit(
-                        &Diagnostic::error()
-                            .with_message("invalid parameter count")
-                            .with_labels(vec![
-                                Label::primary((), op.ident.clone())
-                                    .with_message("this function only takes one parameter"),
-                                Label::secondary((), lhs_expr.span())
-                                    .with_message("first parameter provided here"),
-                                Label::secondary((), rhs_expr.span())
-                                    .with_message("second parameter provided here"),
-                            ]),
-                    )
+            Expr::Binop { lhs, rhs, op: dot } => {
+                call(dot, &[&**lhs, &**rhs], ops, scopes, scope, fn_ids, errors)
+            }
+            Expr::Unop { expr, op: dot } => match &**expr {
+                Expr::Args { args, .. } => {
+                    let args: Vec<_> = args.iter().collect();
+                    call(dot, &args, ops, scopes, scope, fn_ids, errors)
                 }
-
-                (
-                    DecoratedExpr::CallExpr(CallExpr {
-                        function: *ident,
-                        p1: Box::new(lhs),
-                        p2: Some(Box::new(rhs)),
-                    }),
-                    ops,
-                )
+                expr => call(dot, &[expr], ops, scopes, scope, fn_ids, errors),
+            },
+            Expr::Args { l, args, r } => {
+                errors.push(
+                    Diagnostic::error()
+                        .with_message("operands without operation")
+                        .with_labels(vec![Label::primary((), (l.start)..(r.end))
+                            .with_message("these operands are not passed to a function")])
+                        .with_notes(vec![
+                            "put a `.` after the `)` to call a function with them".to_string()
+                        ]),
+                );
+                let mut ops = ops;
+                let mut decorated = Vec::new();
+                for arg in args {
+                    let (arg, rest) = inner(arg, ops, scopes, scope, fn_ids, errors);
+                    decorated.push(arg);
+                    ops = rest;
+                }
+                (decorated.swap_remove(0), ops)
             }
-            Expr::Unop {
-                expr: expr_expr,
-                op: dot,
-            } => {
-                let (expr, ops) = inner(expr_expr, ops, ids, fn_ids, src, reporter);
-                let (op, ops) = ops.split_first().unwrap_or_else(|| {
-                    reporter.report_and_exit(
-                        &Diagnostic::error()
-                            .with_message("unbound operation")
-                            .with_labels(vec![Label::primary((), dot.clone())
-                                .with_message("this operation is not bound to any function")]),
-                    )
-                });
-
-                let ident = ids.get(&src[op.ident.clone()]).unwrap_or_else(|| {
-                    reporter.report_and_exit(
-                        &Diagnostic::error()
-                            .with_message("unbound operation")
-                            .with_labels(vec![
-                                Label::primary((), op.ident.clone())
-                                    .with_message("this function is not defined"),
-                                Label::secondary((), dot.clone())
-                                    .with_message("for this operation"),
-                            ]),
-                    )
-                });
-                let fun = fn_ids.get(ident).unwrap_or_else(|| {
-                    reporter.report_and_exit(
-                        &Diagnostic::error()
-                            .with_message("operation bound to variable")
-                            .with_labels(vec![
-                                Label::primary((), op.ident.clone())
-                                    .with_message("this is a variable and not a function"),
-                                Label::secondary((), dot.clone())
-                                    .with_message("for this operation"),
-                            ]),
-                    )
-                });
-                if fun.two_params() {
-                    reporter.report_and_exit(
-                        &Diagnostic::error()
-                            .with_message("invalid parameter count")
+            Expr::Paren { expr, .. } => inner(expr, ops, scopes, scope, fn_ids, errors),
+            Expr::Ident(span) => match scopes.value(scope, &src[span.clone()], span.clone()) {
+                Some(ident) if scopes.labels.contains(&ident.id) => {
+                    errors.push(label_used_as_value(&ident, span.clone()));
+                    (DecoratedExpr::Identifier(ident), ops)
+                }
+                Some(ident) if fn_ids.contains_key(&ident) => {
+                    errors.push(
+                        Diagnostic::error()
+                            .with_message("function used as value")
                             .with_labels(vec![
-                                Label::primary((), op.ident.clone())
-                                    .with_message("this function takes two parameters"),
-                                Label::secondary((), expr_expr.span())
-                                    .with_message("single parameter provided here"),
+                                Label::primary((), span.clone())
+                                    .with_message("this is a function and not a variable"),
+                                Label::secondary((), ident.decl.clone())
+                                    .with_message("function declared here"),
                             ]),
-                    )
+                    );
+                    (DecoratedExpr::Identifier(ident), ops)
                 }
-
-                (
-                    DecoratedExpr::CallExpr(CallExpr {
-                        function: *ident,
-                        p1: Box::new(expr),
-                        p2: None,
-                    }),
-                    ops,
-                )
-            }
-            Expr::Paren { expr, .. } => inner(expr, ops, ids, fn_ids, src, reporter),
-            Expr::Ident(span) => {
-                let ident = ids.get(&src[span.clone()]).unwrap_or_else(|| {
-                    reporter.report_and_exit(
-                        &Diagnostic::error()
+                Some(ident) => (DecoratedExpr::Identifier(ident), ops),
+                None => {
+                    errors.push(
+                        Diagnostic::error()
                             .with_message("unbound identifier")
                             .with_labels(vec![Label::primary((), span.clone())
                                 .with_message("this variable is not defined")]),
-                    )
-                });
-                (DecoratedExpr::Identifier(*ident), ops)
-            }
+                    );
+                    // Placeholder, the statement is thrown away along with the error
+                    let ident = Identifier {
+                        id: ARGC_ID,
+                        name: src[span.clone()].to_owned(),
+                        decl: span.clone(),
+                    };
+                    (DecoratedExpr::Identifier(ident), ops)
+                }
+            },
         }
     }
 
-    let (expr, ops) = inner(expr, ops, ids, fn_ids, src, reporter);
+    let (expr, ops) = inner(expr, ops, scopes, scope, fn_ids, errors);
     if !ops.is_empty() {
-        reporter.report_and_exit(
-            &Diagnostic::error()
+        errors.push(
+            Diagnostic::error()
                 .with_message("extranuous operations")
                 .with_labels(
                     ops.iter()
@@ -625,7 +1223,253 @@ fn zip_ops_with_expr<'a>(
                         })
                         .collect(),
                 ),
-        )
+        );
     }
     expr
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Callable, DecoratedExpr, DecoratedStmt, ReturnStmt};
+    use crate::{ast1, lexer};
+
+    fn errors(src: &str, error_limit: usize) -> Vec<String> {
+        let parser = ast1::parser(lexer::lexer(src, "test.beatsaber"));
+        super::parse(parser, error_limit, &[])
+            .unwrap_err()
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect()
+    }
+
+    const TYPOS: &str = "// one is 1
+// two is $
+x. // yeet is foo
+// inc is not here
+one.one // yeet is inc
+// three is is
+one. // yeet is inc
+// yeet is 5
+// yeet is \"x\"
+// yeet is not here
+// yeet is with a
+// a is inc
+";
+
+    #[test]
+    fn collects_errors() {
+        assert_eq!(
+            errors(TYPOS, 0),
+            [
+                "unexpected token `<unknown>` as assignment value",
+                "unbound identifier",
+                "unbound operation",
+                "invalid parameter count",
+                "unexpected token `is` as assignment value",
+                "a literal needs a name",
+                "a literal needs a name",
+                "an external function needs a name",
+                "a function needs a name",
+                "operation without an expression",
+            ]
+        );
+    }
+
+    #[test]
+    fn identifier_names() {
+        let src = "// fib is with n\nn // still in fib return is\n";
+        let parser = ast1::parser(lexer::lexer(src, "test.beatsaber"));
+        let ast = super::parse(parser, 0, &[]).unwrap();
+        let block = match &ast[..] {
+            [DecoratedStmt::Callable(Callable::FuncBlock(block))] => block,
+            ast => panic!("expected a single function, got {:?}", ast),
+        };
+        assert_eq!(block.decl.id.name, "fib");
+        assert_eq!(&src[block.decl.id.decl.clone()], "fib");
+        match &block.block[..] {
+            [DecoratedStmt::ReturnStmt(ReturnStmt {
+                expr: DecoratedExpr::Identifier(n),
+                ..
+            })] => {
+                assert_eq!(n.name, "n");
+                assert_eq!(n.decl, block.decl.params[0].decl);
+            }
+            body => panic!("expected a return, got {:?}", body),
+        }
+    }
+
+    #[test]
+    fn scopes() {
+        let src = "// inc is not here\n// a is 1\n// f is with a\na. // still in f return is inc\n// g is 2\n// g is with b\n";
+        let parser = ast1::parser(lexer::lexer(src, "test.beatsaber"));
+        let ast = super::parse(parser, 0, &[]).unwrap();
+        let global = ast
+            .iter()
+            .find_map(|stmt| match stmt {
+                DecoratedStmt::LoadLiteralNumber(stmt) if stmt.ident.name == "a" => {
+                    Some(stmt.ident.clone())
+                }
+                _ => None,
+            })
+            .unwrap();
+        let f = ast
+            .iter()
+            .find_map(|stmt| match stmt {
+                DecoratedStmt::Callable(Callable::FuncBlock(block))
+                    if block.decl.id.name == "f" =>
+                {
+                    Some(block)
+                }
+                _ => None,
+            })
+            .unwrap();
+        // The parameter shadows the global, so `f` captures nothing
+        assert_ne!(f.decl.params[0], global);
+        assert!(super::find_captures(&f.block, &[f.decl.params[0].id]).is_empty());
+        // `g` was a value until the function shadowed it
+        assert!(ast.iter().any(|stmt| matches!(
+            stmt,
+            DecoratedStmt::Callable(Callable::FuncBlock(block)) if block.decl.id.name == "g"
+        )));
+    }
+
+    #[test]
+    fn scope_errors() {
+        let src = "// inc is not here\n// inc is 1\n// f is with a and a\n// b is 1\n// g is with x\nb. // still in g c is inc\n// still in g b is 2\n// h is with inc\n";
+        assert_eq!(
+            errors(src, 0),
+            [
+                "function shadowed",
+                "duplicate parameter",
+                "ambiguous capture",
+                "function shadowed",
+            ]
+        );
+    }
+
+    #[test]
+    fn n_ary() {
+        let src = "// f is with a and b and c\n(a and b and c). // still in f return is f\n// g is not here this is big 1\n// x is 1\n(x and x). // yeet is f\n(x and x and x) // yeet is\n// h is with a and b and a\n";
+        assert_eq!(
+            errors(src, 0),
+            [
+                "invalid parameter count",
+                "invalid parameter count",
+                "operands without operation",
+                "duplicate parameter",
+            ]
+        );
+    }
+
+    #[test]
+    fn variadic() {
+        let src = "// printf is not here this is big or more\n// f is \"%d\"\n(f and f and f). // yeet is printf\nf.f // yeet is printf\nf. // yeet is printf\n";
+        assert_eq!(errors(src, 0), ["invalid parameter count"]);
+    }
+
+    #[test]
+    fn labels() {
+        let src = "end // goto is\n// f is with x\n// still in f here is top\ntop // still in f goto is\n// here is end\n";
+        let parser = ast1::parser(lexer::lexer(src, "test.beatsaber"));
+        assert!(super::parse(parser, 0, &[]).is_ok());
+
+        let src = "// here is a\n// here is a\nnope // goto is\n// one is 1\na.one // b is add\n// a is 2\n// add is not here this is big\n";
+        assert_eq!(
+            errors(src, 0),
+            [
+                "duplicate label",
+                "unknown label",
+                "label used as value",
+                "unbound operation",
+                "name already used by a label",
+            ]
+        );
+    }
+
+    #[test]
+    fn here_as_name() {
+        // Only `here is name` without an expression is a label
+        let src = "// here is 1\n// f is with here here for all\nhere. // still in f here is inc\nhere // still in f return is\n// g is with x here\n// here is top\nhere. // yeet is f\n";
+        let parser = ast1::parser(lexer::lexer(src, "test.beatsaber"));
+        let ast = super::parse(parser, 0, crate::prelude::FUNCTIONS).unwrap();
+        let labels = ast
+            .iter()
+            .filter(|stmt| matches!(stmt, DecoratedStmt::Label(_)))
+            .count();
+        assert_eq!(labels, 1);
+        assert!(ast.iter().any(|stmt| matches!(
+            stmt,
+            DecoratedStmt::Callable(Callable::FuncBlock(f)) if f.decl.id.name == "f" && f.decl.export.is_some()
+        )));
+    }
+
+    #[test]
+    fn prelude() {
+        let src = "// one is 1\n// add is not here this is big\none.one // two is add\ntwo. // yeet is inc\n";
+        let parser = ast1::parser(lexer::lexer(src, "test.beatsaber"));
+        let ast = super::parse(parser, 0, crate::prelude::FUNCTIONS).unwrap();
+        // Only the functions that are used are declared, `add` where the file declares it
+        let mut externs: Vec<_> = ast
+            .iter()
+            .filter_map(|stmt| match stmt {
+                DecoratedStmt::Callable(Callable::ExternFunction(f)) => Some((&*f.name, f.line)),
+                _ => None,
+            })
+            .collect();
+        externs.sort_unstable();
+        assert_eq!(externs, [("add", 2), ("inc", 0)]);
+
+        let src = "// add is not here\n// sub is 1\n// here is xor\n// f is with inc\n";
+        let parser = ast1::parser(lexer::lexer(src, "test.beatsaber"));
+        let errors: Vec<_> = super::parse(parser, 0, crate::prelude::FUNCTIONS)
+            .unwrap_err()
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "prelude function declared with different parameters",
+                "function shadowed",
+                "function shadowed",
+                "function shadowed",
+            ]
+        );
+    }
+
+    #[test]
+    fn export() {
+        let src = "// fib is with n here for all\n// count is 0\n// f is with n here for all\nn.count // still in f return is add\n";
+        let parser = ast1::parser(lexer::lexer(src, "test.beatsaber"));
+        let errors = super::parse(parser, 0, crate::prelude::FUNCTIONS).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "exported function captures a value");
+
+        let parser = ast1::parser(lexer::lexer(&src[..30], "test.beatsaber"));
+        let ast = super::parse(parser, 0, &[]).unwrap();
+        assert!(matches!(
+            &ast[..],
+            [DecoratedStmt::Callable(Callable::FuncBlock(f))] if f.decl.export == Some(17..29)
+        ));
+    }
+
+    #[test]
+    fn deterministic() {
+        let src = include_str!("../examples/bf.beatsaber");
+        let parse = || {
+            let parser = ast1::parser(lexer::lexer(src, "bf.beatsaber"));
+            format!(
+                "{:?}",
+                super::parse(parser, 0, crate::prelude::FUNCTIONS).unwrap()
+            )
+        };
+        assert_eq!(parse(), parse());
+    }
+
+    #[test]
+    fn error_limit() {
+        let errors = errors(TYPOS, 2);
+        assert_eq!(errors.len(), 3);
+        assert!(errors[2].starts_with("aborting after 2 errors"));
+    }
+}
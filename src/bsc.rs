@@ -1,23 +1,49 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
-use beatsaber::codegen::{self, CodegenOptions};
-use beatsaber::{ast1, ast2, lexer};
+use anyhow::{anyhow, Result};
+#[cfg(feature = "llvm")]
+use beatsaber::codegen::{self, CodegenOptions, Emit, RunOptions};
+use beatsaber::interp::Interpreter;
+use beatsaber::link::{self, Library};
+#[cfg(feature = "llvm")]
+use beatsaber::CompileError;
+use beatsaber::{ast2, prelude, Diagnostic, Reporter};
 use clap::{AppSettings, Clap};
 use std::fs;
 
 #[derive(Clap)]
 #[clap(version = "0.1.0", author = "untitled")]
 #[clap(setting = AppSettings::ColoredHelp)]
+#[cfg_attr(not(feature = "llvm"), allow(dead_code))]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
     /// Input source file path.
-    input: String,
-    /// Output object file path.
+    input: Option<String>,
+    /// Output file path, used as the file stem if several outputs are written
     #[clap(short, default_value = "a.out")]
     output: PathBuf,
+    /// Output kinds to write, several can be requested at once
+    #[clap(
+        long,
+        default_value = "exe",
+        multiple_occurrences = true,
+        use_delimiter = true,
+        possible_values = &["llvm-ir", "llvm-bc", "asm", "obj", "exe", "staticlib", "cdylib", "header"]
+    )]
+    emit: Vec<String>,
     /// Target triple
     #[clap(long)]
     target: Option<String>,
+    /// CPU to generate code for: `generic` runs on any CPU of the target, `native` only on this one
+    #[clap(long, default_value = "generic")]
+    cpu: String,
+    /// LLVM target features to enable with + or disable with -, like `+avx2,-sse4.1`
+    #[clap(long, multiple_occurrences = true, use_delimiter = true)]
+    target_feature: Vec<String>,
+    /// Print the targets LLVM supports, or the CPUs and features of the target, and exit
+    #[clap(long, possible_values = &["target-list", "target-cpus"])]
+    print: Option<String>,
     /// Optimization level
     #[clap(short = 'O', default_value = "2")]
     optimization: u8,
@@ -27,30 +53,264 @@ struct Args {
     /// C source files to compile and link
     #[clap(short = 'I')]
     include_c: Vec<String>,
+    /// Directories searched for `but is in` libraries, after the directory of the input
+    #[clap(short = 'L')]
+    library_paths: Vec<PathBuf>,
+    /// Emit DWARF debug info
+    #[clap(short = 'g')]
+    debug_info: bool,
+    /// Don't check that computed gotos land in the current function
+    #[clap(long)]
+    no_goto_checks: bool,
+    /// Call the stdlib.c arithmetic helpers instead of inlining them, for debugging
+    #[clap(long)]
+    no_intrinsics: bool,
+    /// Build a library: no main, top-level functions are exported under their own names
+    #[clap(long)]
+    no_main: bool,
+    /// Keep the intermediate `.tmp` objects of the link step for inspection
+    #[clap(long)]
+    keep_temps: bool,
+    /// Stop after this many errors, 0 for no limit [default: 20]
+    #[clap(long, global = true)]
+    error_limit: Option<usize>,
+    /// Don't link stdlib.c or make its functions available without declarations
+    #[clap(long, global = true)]
+    no_std: bool,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+#[derive(Clap)]
+enum Command {
+    /// JIT compile a program and run it in-process
+    Run(Run),
+    /// Run a program with the interpreter, without LLVM
+    Interp(Interp),
+    /// Rewrite source files in the canonical layout
+    Fmt(Fmt),
+}
 
-    let src = fs::read_to_string(&args.input).unwrap();
-    let lexer = lexer::lexer(&src, &args.input);
-    let parser = ast1::parser(lexer);
-    let ast2 = ast2::parse(parser);
+#[derive(Clap)]
+#[cfg_attr(not(feature = "llvm"), allow(dead_code))]
+struct Run {
+    /// Input source file path.
+    input: String,
+    /// Optimization level
+    #[clap(short = 'O', default_value = "2")]
+    optimization: u8,
+    /// Don't check that computed gotos land in the current function
+    #[clap(long)]
+    no_goto_checks: bool,
+    /// Call the stdlib.c arithmetic helpers instead of inlining them, for debugging
+    #[clap(long)]
+    no_intrinsics: bool,
+    /// Directories searched for `but is in` libraries, after the directory of the input
+    #[clap(short = 'L')]
+    library_paths: Vec<PathBuf>,
+    /// Arguments passed to the program
+    #[clap(last = true)]
+    args: Vec<String>,
+}
+
+#[derive(Clap)]
+struct Interp {
+    /// Input source file path.
+    input: String,
+    /// Arguments passed to the program
+    #[clap(last = true)]
+    args: Vec<String>,
+}
+
+#[derive(Clap)]
+struct Fmt {
+    /// Source files to format
+    #[clap(required = true)]
+    inputs: Vec<String>,
+    /// Only check the layout, exiting with an error if a file would change
+    #[clap(long)]
+    check: bool,
+}
+
+#[cfg(feature = "llvm")]
+fn opt_level(level: u8) -> codegen::OptLevel {
+    match level {
+        0 => codegen::OptLevel::None,
+        1 => codegen::OptLevel::Less,
+        2 => codegen::OptLevel::Default,
+        3 => codegen::OptLevel::Aggressive,
+        _ => panic!("Invalid optimization level"),
+    }
+}
+
+fn report(src: &str, input: &str, diagnostics: &[Diagnostic]) -> ! {
+    let reporter = Reporter::new(src, input);
+    for diagnostic in diagnostics {
+        reporter.report(diagnostic);
+    }
+    std::process::exit(1)
+}
+
+/// Parse a file, exiting on errors and printing any warnings. Returns the source and the AST.
+fn parse(
+    input: &str,
+    error_limit: usize,
+    no_std: bool,
+) -> Result<(String, Vec<ast2::DecoratedStmt>)> {
+    let src = fs::read_to_string(input)?;
+    let prelude = if no_std { &[] } else { prelude::FUNCTIONS };
+    let ast = beatsaber::parse_str_with_prelude(&src, input, error_limit, prelude)
+        .unwrap_or_else(|d| report(&src, input, &d));
+    let reporter = Reporter::new(&src, input);
+    for warning in beatsaber::goto::lint(&ast) {
+        reporter.report(&warning);
+    }
+    Ok((src, ast))
+}
+
+/// Find the libraries named by `but is in`, exiting if any is missing.
+fn libraries(
+    input: &str,
+    src: &str,
+    ast: &[ast2::DecoratedStmt],
+    library_paths: &[PathBuf],
+    target: Option<&str>,
+) -> Vec<Library> {
+    let dirs = link::search_dirs(Path::new(input), library_paths);
+    link::resolve(ast, &dirs, target).unwrap_or_else(|d| report(src, input, &d))
+}
+
+#[cfg(feature = "llvm")]
+fn build(args: Args, error_limit: usize) -> Result<()> {
+    match args.print.as_deref() {
+        Some("target-list") => {
+            for (name, description) in codegen::target_list() {
+                println!("{:<12} - {}", name, description);
+            }
+            return Ok(());
+        }
+        Some("target-cpus") => return codegen::print_target_cpus(args.target.as_deref()),
+        _ => {}
+    }
+    let input = args
+        .input
+        .ok_or_else(|| anyhow!("no input file provided"))?;
+    let mut emit = Vec::new();
+    for kind in &args.emit {
+        let kind = match kind.as_str() {
+            "llvm-ir" => Emit::LlvmIr,
+            "llvm-bc" => Emit::LlvmBc,
+            "asm" => Emit::Asm,
+            "obj" => Emit::Obj,
+            "exe" => Emit::Exe,
+            "staticlib" => Emit::StaticLib,
+            "cdylib" => Emit::Cdylib,
+            "header" => Emit::Header,
+            _ => unreachable!(),
+        };
+        if !emit.contains(&kind) {
+            emit.push(kind);
+        }
+    }
     let options = CodegenOptions {
         output: args.output.as_path(),
-        optimization: match args.optimization {
-            0 => codegen::OptLevel::None,
-            1 => codegen::OptLevel::Less,
-            2 => codegen::OptLevel::Default,
-            3 => codegen::OptLevel::Aggressive,
-            _ => panic!("Invalid optimization level"),
-        },
+        emit,
+        optimization: opt_level(args.optimization),
         pic: args.pic,
         target: args.target,
+        cpu: args.cpu,
+        target_features: args.target_feature,
         include_c: args.include_c,
+        debug_info: args.debug_info,
+        goto_checks: !args.no_goto_checks,
+        libraries: Vec::new(),
+        library_paths: args.library_paths,
+        std: !args.no_std,
+        // Without the standard library the names can be the program's own functions
+        intrinsics: !args.no_intrinsics && !args.no_std,
+        keep_temps: args.keep_temps,
+        main: !args.no_main,
+    };
+    let src = fs::read_to_string(&input)?;
+    match beatsaber::compile_str(&src, &input, error_limit, options) {
+        Ok(artifact) => {
+            let reporter = Reporter::new(&src, &input);
+            for warning in &artifact.warnings {
+                reporter.report(warning);
+            }
+            Ok(())
+        }
+        Err(CompileError::Parse(diagnostics)) => report(&src, &input, &diagnostics),
+        Err(CompileError::Build(e)) => Err(e),
+    }
+}
+
+#[cfg(feature = "llvm")]
+fn run(run: Run, error_limit: usize, no_std: bool) -> Result<()> {
+    let (src, ast2) = parse(&run.input, error_limit, no_std)?;
+    let libraries = libraries(&run.input, &src, &ast2, &run.library_paths, None);
+    let mut program_args = vec![run.input];
+    program_args.extend(run.args);
+    let options = RunOptions {
+        optimization: opt_level(run.optimization),
+        args: &program_args,
+        goto_checks: !run.no_goto_checks,
+        libraries,
+        intrinsics: !run.no_intrinsics && !no_std,
     };
-    // dbg!(&ast2);
-    codegen::Codegen::compile(ast2, options)?;
+    let code = codegen::Codegen::run(ast2, options)?;
+    std::process::exit(code);
+}
+
+#[cfg(not(feature = "llvm"))]
+fn build(_: Args, _: usize) -> Result<()> {
+    Err(anyhow!(
+        "bsc was built without LLVM, only `bsc interp` is available"
+    ))
+}
+
+#[cfg(not(feature = "llvm"))]
+fn run(_: Run, _: usize, _: bool) -> Result<()> {
+    Err(anyhow!(
+        "bsc was built without LLVM, use `bsc interp` instead"
+    ))
+}
 
+fn interp(interp: Interp, error_limit: usize, no_std: bool) -> Result<()> {
+    let (_, ast2) = parse(&interp.input, error_limit, no_std)?;
+    let mut program_args = vec![interp.input];
+    program_args.extend(interp.args);
+    let code = Interpreter::new().run(&ast2, &program_args)?;
+    std::process::exit(code as i32);
+}
+
+fn fmt(fmt: Fmt) -> Result<()> {
+    let mut unformatted = false;
+    for input in &fmt.inputs {
+        let src = fs::read_to_string(input)?;
+        let formatted = beatsaber::fmt::format(&src).unwrap_or_else(|d| report(&src, input, &d));
+        if formatted == src {
+            continue;
+        }
+        if fmt.check {
+            eprintln!("{} is not formatted", input);
+            unformatted = true;
+        } else {
+            fs::write(input, formatted)?;
+        }
+    }
+    if unformatted {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+fn main() -> Result<()> {
+    let mut args = Args::parse();
+    let error_limit = args.error_limit.unwrap_or(beatsaber::DEFAULT_ERROR_LIMIT);
+
+    match args.command.take() {
+        Some(Command::Run(r)) => run(r, error_limit, args.no_std),
+        Some(Command::Interp(i)) => interp(i, error_limit, args.no_std),
+        Some(Command::Fmt(f)) => fmt(f),
+        None => build(args, error_limit),
+    }
+}
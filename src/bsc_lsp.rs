@@ -0,0 +1,473 @@
+//! Language server for beatsaber, speaking LSP over stdio.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use beatsaber::symbols::{self, Symbol, SymbolKind};
+use beatsaber::Diagnostic;
+use codespan_reporting::diagnostic::{LabelStyle, Severity};
+use lsp_server::{Connection, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+    PublishDiagnostics,
+};
+use lsp_types::request::{Completion, GotoDefinition, HoverRequest, Request as _};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    DiagnosticSeverity, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents,
+    HoverParams, HoverProviderCapability, Location, MarkedString, OneOf, Position,
+    PublishDiagnosticsParams, Range, ServerCapabilities, TextDocumentSyncCapability,
+    TextDocumentSyncKind, Url,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Sync + Send>>;
+
+/// Convert a byte offset into an LSP position, which counts UTF-16 code units.
+fn position(src: &str, offset: usize) -> Position {
+    let before = &src[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Position {
+        line: before.matches('\n').count() as u32,
+        character: before[line_start..].encode_utf16().count() as u32,
+    }
+}
+
+/// Convert an LSP position into a byte offset, clamped to the end of its line.
+fn offset(src: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match src[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return src.len(),
+        }
+    }
+    let line = src[line_start..].split('\n').next().unwrap_or("");
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= position.character as usize {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    line_start + line.len()
+}
+
+fn range(src: &str, span: std::ops::Range<usize>) -> Range {
+    Range::new(position(src, span.start), position(src, span.end))
+}
+
+fn lsp_diagnostic(src: &str, diagnostic: &Diagnostic) -> lsp_types::Diagnostic {
+    let primary = diagnostic
+        .labels
+        .iter()
+        .find(|label| label.style == LabelStyle::Primary)
+        .or_else(|| diagnostic.labels.first());
+    let mut message = diagnostic.message.clone();
+    if let Some(label) = primary.filter(|label| !label.message.is_empty()) {
+        message = format!("{}: {}", message, label.message);
+    }
+    for note in &diagnostic.notes {
+        message = format!("{}\n{}", message, note);
+    }
+    let severity = match diagnostic.severity {
+        Severity::Bug | Severity::Error => DiagnosticSeverity::ERROR,
+        Severity::Warning => DiagnosticSeverity::WARNING,
+        Severity::Note => DiagnosticSeverity::INFORMATION,
+        Severity::Help => DiagnosticSeverity::HINT,
+    };
+    lsp_types::Diagnostic {
+        range: primary.map_or_else(Range::default, |label| range(src, label.range.clone())),
+        severity: Some(severity),
+        source: Some("bsc".to_owned()),
+        message,
+        ..Default::default()
+    }
+}
+
+fn describe(symbol: &Symbol) -> String {
+    let plural = |params| {
+        if params == 1 {
+            "parameter"
+        } else {
+            "parameters"
+        }
+    };
+    let kind = match &symbol.kind {
+        SymbolKind::Function { params } => format!("function with {} {}", params, plural(*params)),
+        SymbolKind::Extern {
+            params,
+            module,
+            variadic,
+        } => {
+            let mut kind = format!("external function with {} {}", params, plural(*params));
+            if *params >= 2 {
+                kind.push_str(" (this is big)");
+            }
+            if *variadic {
+                kind.push_str(" or more");
+            }
+            if let Some(module) = module {
+                kind = format!("{} in {}", kind, module);
+            }
+            kind
+        }
+        SymbolKind::Value => "value".to_owned(),
+        SymbolKind::Label => "label".to_owned(),
+    };
+    if symbol.line == 0 {
+        return format!("{}: {} from the standard library", symbol.name, kind);
+    }
+    format!(
+        "{}: {}, declared on line {}",
+        symbol.name, kind, symbol.line
+    )
+}
+
+/// Whether the cursor is on an operation name, right after `is` or `then`.
+fn completes_operation(src: &str, offset: usize) -> bool {
+    let line_start = src[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let before =
+        src[line_start..offset].trim_end_matches(|c: char| c.is_ascii_alphanumeric() || c == '_');
+    let mut words = before.split_whitespace();
+    before.ends_with(char::is_whitespace) && matches!(words.next_back(), Some("is" | "then"))
+}
+
+struct Server {
+    connection: Connection,
+    documents: HashMap<Url, String>,
+}
+
+impl Server {
+    fn publish_diagnostics(&self, uri: Url) -> Result<()> {
+        let src = &self.documents[&uri];
+        let diagnostics = match beatsaber::parse_str(src, uri.as_str(), 0) {
+            Ok(ast) => beatsaber::goto::lint(&ast)
+                .iter()
+                .map(|diagnostic| lsp_diagnostic(src, diagnostic))
+                .collect(),
+            Err(diagnostics) => diagnostics
+                .iter()
+                .map(|diagnostic| lsp_diagnostic(src, diagnostic))
+                .collect(),
+        };
+        let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+        self.connection
+            .sender
+            .send(Message::Notification(Notification::new(
+                PublishDiagnostics::METHOD.to_owned(),
+                params,
+            )))?;
+        Ok(())
+    }
+
+    fn notification(&mut self, notification: Notification) -> Result<()> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: lsp_types::DidOpenTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                let document = params.text_document;
+                self.documents.insert(document.uri.clone(), document.text);
+                self.publish_diagnostics(document.uri)
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: lsp_types::DidChangeTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                // Full sync, the last change holds the whole document
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    let uri = params.text_document.uri;
+                    self.documents.insert(uri.clone(), change.text);
+                    self.publish_diagnostics(uri)?;
+                }
+                Ok(())
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: lsp_types::DidCloseTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                self.documents.remove(&params.text_document.uri);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn request(&self, request: Request) -> Result<Response> {
+        let id = request.id.clone();
+        let result = match request.method.as_str() {
+            GotoDefinition::METHOD => {
+                let params: GotoDefinitionParams = serde_json::from_value(request.params)?;
+                serde_json::to_value(self.definition(params))?
+            }
+            HoverRequest::METHOD => {
+                let params: HoverParams = serde_json::from_value(request.params)?;
+                serde_json::to_value(self.hover(params))?
+            }
+            Completion::METHOD => {
+                let params: CompletionParams = serde_json::from_value(request.params)?;
+                serde_json::to_value(self.completion(params))?
+            }
+            _ => {
+                return Ok(Response::new_err(
+                    id,
+                    lsp_server::ErrorCode::MethodNotFound as i32,
+                    format!("unsupported request `{}`", request.method),
+                ))
+            }
+        };
+        Ok(Response {
+            id,
+            result: Some(result),
+            error: None,
+        })
+    }
+
+    fn respond(&self, response: Response) -> Result<()> {
+        self.connection.sender.send(Message::Response(response))?;
+        Ok(())
+    }
+
+    fn symbol_at(&self, uri: &Url, position: Position) -> Option<(&str, Symbol)> {
+        let src = self.documents.get(uri)?;
+        let symbol = symbols::index(src).at(offset(src, position))?.clone();
+        Some((src, symbol))
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let params = params.text_document_position_params;
+        let uri = params.text_document.uri;
+        let (src, symbol) = self.symbol_at(&uri, params.position)?;
+        // Functions of the prelude are not declared anywhere in the file
+        if symbol.line == 0 {
+            return None;
+        }
+        let location = Location::new(uri.clone(), range(src, symbol.decl));
+        Some(GotoDefinitionResponse::Scalar(location))
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let params = params.text_document_position_params;
+        let (_, symbol) = self.symbol_at(&params.text_document.uri, params.position)?;
+        Some(Hover {
+            contents: HoverContents::Scalar(MarkedString::String(describe(&symbol))),
+            range: None,
+        })
+    }
+
+    fn completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let params = params.text_document_position;
+        let src = self.documents.get(&params.text_document.uri)?;
+        let offset = offset(src, params.position);
+        if !completes_operation(src, offset) {
+            return None;
+        }
+        // Operations can only use functions declared on an earlier line
+        let line = params.position.line as usize + 1;
+        let items = symbols::index(src)
+            .iter()
+            .filter(|symbol| {
+                symbol.line < line
+                    && matches!(
+                        symbol.kind,
+                        SymbolKind::Function { .. } | SymbolKind::Extern { .. }
+                    )
+            })
+            .map(|symbol| CompletionItem {
+                label: symbol.name.clone(),
+                kind: Some(CompletionItemKind::FUNCTION),
+                detail: Some(describe(symbol)),
+                ..Default::default()
+            })
+            .collect();
+        Some(CompletionResponse::Array(items))
+    }
+
+    fn main_loop(&mut self) -> Result<()> {
+        while let Ok(message) = self.connection.receiver.recv() {
+            match message {
+                Message::Request(request) => {
+                    if self.connection.handle_shutdown(&request)? {
+                        return Ok(());
+                    }
+                    // A failed request is answered with the error, the server keeps running
+                    let id = request.id.clone();
+                    let method = request.method.clone();
+                    let response = self.request(request).unwrap_or_else(|e| {
+                        eprintln!("bsc-lsp: {} failed: {}", method, e);
+                        Response::new_err(
+                            id,
+                            lsp_server::ErrorCode::InternalError as i32,
+                            e.to_string(),
+                        )
+                    });
+                    self.respond(response)?;
+                }
+                Message::Notification(notification) => {
+                    let method = notification.method.clone();
+                    if let Err(e) = self.notification(notification) {
+                        eprintln!("bsc-lsp: {} failed: {}", method, e);
+                    }
+                }
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![" ".to_owned()]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+fn serve(connection: Connection) -> Result<()> {
+    connection.initialize(serde_json::to_value(capabilities())?)?;
+    Server {
+        connection,
+        documents: HashMap::new(),
+    }
+    .main_loop()
+}
+
+fn main() -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    serve(connection)?;
+    io_threads.join()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_server::RequestId;
+    use serde_json::{json, Value};
+    use std::thread;
+
+    const SRC: &str = "// add is not here this is big
+// fib is with n
+// one is 1
+n.one // still in fib return is add
+// res is 10
+res. // yeet is fib
+x. // yeet is 
+";
+
+    fn respond(client: &Connection, id: i32, method: &str, params: Value) -> Response {
+        let request = Request::new(RequestId::from(id), method.to_owned(), params);
+        client.sender.send(Message::Request(request)).unwrap();
+        loop {
+            match client.receiver.recv().unwrap() {
+                Message::Response(response) => return response,
+                _ => continue,
+            }
+        }
+    }
+
+    fn request(client: &Connection, id: i32, method: &str, params: Value) -> Value {
+        respond(client, id, method, params).result.unwrap()
+    }
+
+    /// The diagnostics published next.
+    fn published(client: &Connection) -> Vec<Value> {
+        match client.receiver.recv().unwrap() {
+            Message::Notification(notification) => notification.params["diagnostics"]
+                .as_array()
+                .unwrap()
+                .clone(),
+            message => panic!("expected diagnostics, got {:?}", message),
+        }
+    }
+
+    fn notify(client: &Connection, method: &str, params: Value) {
+        let notification = Notification::new(method.to_owned(), params);
+        client
+            .sender
+            .send(Message::Notification(notification))
+            .unwrap();
+    }
+
+    fn position(line: u32, character: u32) -> Value {
+        json!({
+            "textDocument": { "uri": "file:///test.beatsaber" },
+            "position": { "line": line, "character": character },
+        })
+    }
+
+    #[test]
+    fn session() {
+        let (server, client) = Connection::memory();
+        let server = thread::spawn(move || serve(server).unwrap());
+
+        request(&client, 0, "initialize", json!({ "capabilities": {} }));
+        notify(&client, "initialized", json!({}));
+        notify(
+            &client,
+            DidOpenTextDocument::METHOD,
+            json!({ "textDocument": {
+                "uri": "file:///test.beatsaber",
+                "languageId": "beatsaber",
+                "version": 0,
+                "text": SRC,
+            }}),
+        );
+        let diagnostics = published(&client);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(
+            diagnostics[0]["range"]["start"],
+            json!({ "line": 6, "character": 0 })
+        );
+
+        // `fib` in `res. // yeet is fib`
+        let definition = request(&client, 1, GotoDefinition::METHOD, position(5, 16));
+        assert_eq!(
+            definition["range"]["start"],
+            json!({ "line": 1, "character": 3 })
+        );
+
+        let hover = request(&client, 2, HoverRequest::METHOD, position(0, 4));
+        assert_eq!(
+            hover["contents"],
+            "add: external function with 2 parameters (this is big), declared on line 1"
+        );
+
+        let completion = request(&client, 3, Completion::METHOD, position(6, 14));
+        let labels: Vec<_> = completion
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["label"].as_str().unwrap())
+            .collect();
+        // `add` is declared by the file, the other functions of the prelude are offered as well
+        assert_eq!(labels.len(), beatsaber::prelude::FUNCTIONS.len() + 1);
+        assert!(labels.contains(&"add") && labels.contains(&"fib") && labels.contains(&"inc"));
+
+        // A line still being typed is reported and leaves the server running
+        notify(
+            &client,
+            DidChangeTextDocument::METHOD,
+            json!({
+                "textDocument": { "uri": "file:///test.beatsaber", "version": 1 },
+                "contentChanges": [{ "text": "// a is inc\n" }],
+            }),
+        );
+        let diagnostics = published(&client);
+        assert_eq!(diagnostics.len(), 1);
+        let message = diagnostics[0]["message"].as_str().unwrap();
+        assert!(message.starts_with("operation without an expression"));
+
+        // Malformed parameters are answered with an error
+        let response = respond(&client, 4, HoverRequest::METHOD, json!({}));
+        assert!(response.result.is_none() && response.error.is_some());
+        request(&client, 5, HoverRequest::METHOD, position(0, 3));
+
+        request(&client, 6, "shutdown", Value::Null);
+        notify(&client, "exit", Value::Null);
+        server.join().unwrap();
+    }
+}
@@ -1,214 +1,906 @@
-use crate::ast2;
-use anyhow::Result;
+use crate::link::Library;
+use crate::{ast2, prelude, runtime};
+use anyhow::{anyhow, bail, Error, Result};
+use inkwell::attributes::AttributeLoc;
 use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
-use inkwell::module::{Linkage, Module};
+use inkwell::debug_info::{
+    AsDIScope, DICompileUnit, DIFlags, DIFlagsConstants, DISubprogram, DIType, DWARFEmissionKind,
+    DWARFSourceLanguage, DebugInfoBuilder,
+};
+use inkwell::module::{FlagBehavior, Linkage, Module};
 use inkwell::targets::{
     CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
 };
 use inkwell::types::{BasicTypeEnum, IntType};
 use inkwell::values::{BasicValueEnum, FunctionValue, GlobalValue, IntValue, PointerValue};
-use inkwell::{AddressSpace, OptimizationLevel};
-use std::collections::{BTreeMap, HashMap};
-use std::io::Write;
-use std::mem;
-use std::path::Path;
-
-/// Look for any identifier that is not declared in this function and assume they are captures.
-fn find_captures(stmts: &[ast2::DecoratedStmt], params: &[usize]) -> Vec<usize> {
-    let mut locals = params.to_vec();
-    let mut captures = Vec::new();
-    fn process_expr(locals: &[usize], captures: &mut Vec<usize>, expr: &ast2::DecoratedExpr) {
-        match expr {
-            ast2::DecoratedExpr::Identifier(ident) => {
-                if !locals.contains(&ident.id) {
-                    captures.push(ident.id);
-                }
-            }
-            ast2::DecoratedExpr::CallExpr(expr) => {
-                process_expr(locals, captures, &*expr.p1);
-                if let Some(p2) = &expr.p2 {
-                    process_expr(locals, captures, &*p2);
-                }
-            }
-        }
-    }
-    fn process_stmt(
-        locals: &mut Vec<usize>,
-        captures: &mut Vec<usize>,
-        stmt: &ast2::DecoratedStmt,
-    ) {
+use inkwell::{AddressSpace, IntPredicate, OptimizationLevel};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::{mem, ptr};
+
+/// Lines holding a statement, in any function.
+fn statement_lines(stmts: &[ast2::DecoratedStmt]) -> BTreeSet<usize> {
+    let mut lines = BTreeSet::new();
+    fn process_stmt(lines: &mut BTreeSet<usize>, stmt: &ast2::DecoratedStmt) {
         match stmt {
-            ast2::DecoratedStmt::LoadLiteralNumber(stmt) => {
-                locals.push(stmt.ident.id);
-            }
             ast2::DecoratedStmt::Callable(ast2::Callable::FuncBlock(block)) => {
-                locals.push(block.decl.p1.id);
-                if let Some(p2) = block.decl.p2 {
-                    locals.push(p2.id);
-                }
                 for stmt in &block.block {
-                    process_stmt(locals, captures, stmt);
+                    process_stmt(lines, stmt);
                 }
             }
-            ast2::DecoratedStmt::Conditional(stmt) => {
-                process_stmt(locals, captures, &*stmt.success);
-            }
-            ast2::DecoratedStmt::Assignment(stmt) => {
-                process_expr(locals, captures, &stmt.value);
-                if let Some(ident) = stmt.name {
-                    locals.push(ident.id);
-                }
+            ast2::DecoratedStmt::Callable(_) => {}
+            _ => {
+                lines.insert(stmt.line_number());
             }
-            ast2::DecoratedStmt::ReturnStmt(stmt) => {
-                process_expr(locals, captures, &stmt.expr);
-            }
-            _ => {}
         }
     }
     for stmt in stmts {
-        process_stmt(&mut locals, &mut captures, stmt);
+        process_stmt(&mut lines, stmt);
     }
-    captures
+    lines
 }
 
-fn get_line_count(stmts: &[ast2::DecoratedStmt]) -> u32 {
-    let mut count = 0;
-    fn process_stmt(count: &mut u32, stmt: &ast2::DecoratedStmt) {
-        if let ast2::DecoratedStmt::Callable(ast2::Callable::FuncBlock(block)) = stmt {
-            for stmt in &block.block {
-                process_stmt(count, stmt);
-            }
+pub type OptLevel = OptimizationLevel;
+
+/// Kind of output file to write.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Emit {
+    LlvmIr,
+    LlvmBc,
+    Asm,
+    Obj,
+    Exe,
+    StaticLib,
+    Cdylib,
+    /// C prototypes of the exported functions
+    Header,
+}
+
+impl Emit {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Emit::LlvmIr => "ll",
+            Emit::LlvmBc => "bc",
+            Emit::Asm => "s",
+            Emit::Obj => "o",
+            Emit::Header => "h",
+            Emit::Exe | Emit::StaticLib | Emit::Cdylib => "",
         }
-        *count = (*count).max(stmt.line_number() as u32);
     }
-    for stmt in stmts {
-        process_stmt(&mut count, stmt);
+
+    /// Whether the output is linked with the C files, these always use `output` as is.
+    pub fn linked(self) -> bool {
+        matches!(self, Emit::Exe | Emit::StaticLib | Emit::Cdylib)
     }
-    count
 }
 
-pub type OptLevel = OptimizationLevel;
-
 pub struct CodegenOptions<'a> {
     pub output: &'a Path,
+    /// Outputs to write, `output` is used as the file stem if there are several
+    pub emit: Vec<Emit>,
     pub optimization: OptLevel,
     /// Position Independent Code
     pub pic: bool,
     /// Target triple, None for host
     pub target: Option<String>,
+    /// CPU to generate code for, `generic` for any CPU of the target or `native` for this one
+    pub cpu: String,
+    /// LLVM features added to those of the CPU, like `+avx2` or `-sse4.1`
+    pub target_features: Vec<String>,
     pub include_c: Vec<String>,
+    /// Emit DWARF debug info
+    pub debug_info: bool,
+    /// Abort when a computed goto lands outside of the current function
+    pub goto_checks: bool,
+    /// Libraries named by `but is in`, see `link::resolve`
+    pub libraries: Vec<Library>,
+    /// Directories `compile_str` searches for the libraries, after the one of the source
+    pub library_paths: Vec<PathBuf>,
+    /// Link stdlib.c, false for `--no-std`
+    pub std: bool,
+    /// Build calls to stdlib.c arithmetic as instructions, see `Intrinsic`
+    pub intrinsics: bool,
+    /// Leave the intermediate objects and C sources of the link step next to the output
+    pub keep_temps: bool,
+    /// Generate a `main` running the top-level statements. Without it, the top-level functions
+    /// are exported under their own names for C code to call
+    pub main: bool,
+}
+
+impl<'a> CodegenOptions<'a> {
+    /// Options for an optimized executable at `output`, like `bsc` without flags.
+    pub fn new(output: &'a Path) -> Self {
+        CodegenOptions {
+            output,
+            emit: vec![Emit::Exe],
+            optimization: OptLevel::Default,
+            pic: false,
+            target: None,
+            cpu: "generic".to_string(),
+            target_features: Vec::new(),
+            include_c: Vec::new(),
+            debug_info: false,
+            goto_checks: true,
+            libraries: Vec::new(),
+            library_paths: Vec::new(),
+            std: true,
+            intrinsics: true,
+            keep_temps: false,
+            main: true,
+        }
+    }
+}
+
+/// Files written by a successful compilation.
+pub struct Artifact {
+    pub outputs: Vec<(Emit, PathBuf)>,
+    /// Warnings about the program, filled in by `compile_str`, see `goto::lint`
+    pub warnings: Vec<crate::Diagnostic>,
+}
+
+pub struct RunOptions<'a> {
+    pub optimization: OptLevel,
+    /// Program arguments, including the program name
+    pub args: &'a [String],
+    /// Abort when a computed goto lands outside of the current function
+    pub goto_checks: bool,
+    /// Libraries named by `but is in`, loaded into the process before running
+    pub libraries: Vec<Library>,
+    /// Build calls to stdlib.c arithmetic as instructions, see `Intrinsic`
+    pub intrinsics: bool,
+}
+
+/// A failed run of the C compiler while building an executable.
+///
+/// Returned wrapped in an `anyhow::Error`, so callers that care can downcast to it.
+#[derive(Debug)]
+pub enum BuildError {
+    /// A C file, from `-I` or bundled with the compiler, failed to compile
+    CompileC {
+        file: PathBuf,
+        command: String,
+        output: String,
+    },
+    /// Linking the objects into the executable failed
+    Link { command: String, output: String },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (command, output) = match self {
+            BuildError::CompileC {
+                file,
+                command,
+                output,
+            } => {
+                writeln!(f, "C include {} failed to compile", file.display())?;
+                (command, output)
+            }
+            BuildError::Link { command, output } => {
+                writeln!(f, "link failed")?;
+                (command, output)
+            }
+        };
+        write!(f, "command: {}", command)?;
+        if !output.is_empty() {
+            write!(f, "\n{}", output)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// What a failed compiler run printed. MSVC reports errors on stdout.
+fn captured(result: &Output) -> String {
+    let mut output = String::from_utf8_lossy(&result.stdout).into_owned();
+    output.push_str(&String::from_utf8_lossy(&result.stderr));
+    output.trim_end().to_owned()
+}
+
+/// The triple and LLVM target for `--target`, the host if there is none.
+fn lookup_target(target: Option<&str>) -> Result<(TargetTriple, Target)> {
+    Target::initialize_all(&InitializationConfig::default());
+    let triple = match target {
+        Some(triple) => TargetTriple::create(triple),
+        None => TargetMachine::get_default_triple(),
+    };
+    let target = Target::from_triple(&triple).map_err(|e| {
+        anyhow!(
+            "invalid target {}: {}",
+            triple.as_str().to_string_lossy(),
+            e
+        )
+    })?;
+    Ok((triple, target))
+}
+
+/// A target machine for `--target`, `cpu` can be `native` for the host CPU.
+fn create_target_machine(
+    target: Option<&str>,
+    cpu: &str,
+    target_features: &[String],
+    opt: OptLevel,
+    reloc: RelocMode,
+) -> Result<TargetMachine> {
+    let (triple, llvm_target) = lookup_target(target)?;
+    let (cpu, mut features) = if cpu == "native" {
+        if target.is_some() {
+            bail!("--cpu=native only describes the host, pick a CPU of the target instead");
+        }
+        let cpu = TargetMachine::get_host_cpu_name().to_string();
+        let features = TargetMachine::get_host_cpu_features().to_string();
+        (cpu, vec![features])
+    } else {
+        (cpu.to_owned(), Vec::new())
+    };
+    for feature in target_features {
+        if !feature.starts_with(|c| c == '+' || c == '-') {
+            bail!("target feature {} must start with + or -", feature);
+        }
+        features.push(feature.clone());
+    }
+    let features = features.join(",");
+    let create = || {
+        llvm_target.create_target_machine(&triple, &cpu, &features, opt, reloc, CodeModel::Default)
+    };
+    // LLVM only warns about a CPU or feature it doesn't know, and goes on without it
+    #[cfg(unix)]
+    let machine = {
+        let (machine, warnings) = capture_stderr(create)?;
+        // generic is the default, which isn't the name of a CPU on every target
+        if let Some(warning) = warnings.lines().find(|w| !w.starts_with("'generic' ")) {
+            let warning = warning.split(" (ignoring").next().unwrap_or_default();
+            bail!("{}, see --print target-cpus", warning);
+        }
+        machine
+    };
+    #[cfg(not(unix))]
+    let machine = create();
+    machine.ok_or_else(|| anyhow!("failed to create target machine"))
+}
+
+/// Run `f` with the stderr of the process going to a file, returning what was written to it.
+/// LLVM reports unknown CPUs and features and lists the ones it knows only there.
+#[cfg(unix)]
+fn capture_stderr<T>(f: impl FnOnce() -> T) -> Result<(T, String)> {
+    use std::os::raw::c_int;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::Mutex;
+    extern "C" {
+        fn dup(fd: c_int) -> c_int;
+        fn dup2(fd: c_int, to: c_int) -> c_int;
+        fn close(fd: c_int) -> c_int;
+    }
+    const STDERR: c_int = 2;
+    // Another thread would capture into the same file, or restore stderr too early
+    static LOCK: Mutex<()> = Mutex::new(());
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let path = std::env::temp_dir().join(format!("bsc_stderr_{}", std::process::id()));
+    let file = std::fs::File::create(&path)?;
+    let saved = unsafe { dup(STDERR) };
+    if saved < 0 || unsafe { dup2(file.as_raw_fd(), STDERR) } < 0 {
+        bail!(
+            "failed to capture stderr: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    let result = f();
+    unsafe {
+        dup2(saved, STDERR);
+        close(saved);
+    }
+    drop(file);
+    let captured = String::from_utf8_lossy(&std::fs::read(&path)?).into_owned();
+    let _ = std::fs::remove_file(&path);
+    Ok((result, captured))
+}
+
+/// Name and description of every target LLVM was built with, for `bsc --print target-list`.
+pub fn target_list() -> Vec<(String, String)> {
+    Target::initialize_all(&InitializationConfig::default());
+    let mut targets = Vec::new();
+    let mut next = Target::get_first();
+    while let Some(target) = next {
+        targets.push((
+            target.get_name().to_string_lossy().into_owned(),
+            target.get_description().to_string_lossy().into_owned(),
+        ));
+        next = target.get_next();
+    }
+    targets
+}
+
+/// Print the CPUs and features of a target, for `bsc --print target-cpus`. LLVM only writes
+/// them to stderr, so they are moved to stdout where that is supported.
+pub fn print_target_cpus(target: Option<&str>) -> Result<()> {
+    let (triple, target) = lookup_target(target)?;
+    // LLVM lists what it knows when asked for the CPU `help`
+    let create = || {
+        target.create_target_machine(
+            &triple,
+            "help",
+            "",
+            OptLevel::None,
+            RelocMode::Default,
+            CodeModel::Default,
+        )
+    };
+    #[cfg(unix)]
+    let machine = {
+        let (machine, list) = capture_stderr(create)?;
+        print!("{}", list);
+        machine
+    };
+    #[cfg(not(unix))]
+    let machine = create();
+    machine.ok_or_else(|| anyhow!("failed to create target machine"))?;
+    Ok(())
+}
+
+/// The C compiler for `target`, as picked by the `cc` crate.
+fn c_build(target: &TargetTriple, opt: u32) -> cc::Build {
+    let mut build = cc::Build::new();
+    build
+        .target(target.as_str().to_str().unwrap())
+        .host(env!("HOST"))
+        .opt_level(opt)
+        .cargo_metadata(false);
+    build
+}
+
+/// Bundle `objects` into a static library, replacing any previous one.
+fn archive(target: &TargetTriple, opt: u32, archive: &str, objects: &[String]) -> Result<()> {
+    let build = c_build(target, opt);
+    let mut archiver = build.try_get_archiver()?;
+    if build.try_get_compiler()?.is_like_msvc() {
+        archiver.arg(format!("/OUT:{}", archive));
+    } else {
+        // `ar` adds to an existing archive
+        if let Err(e) = std::fs::remove_file(archive) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+        archiver.arg("crs").arg(archive);
+    }
+    let status = archiver.args(objects).status()?;
+    if !status.success() {
+        bail!("failed to archive {}", archive);
+    }
+    Ok(())
+}
+
+/// Intermediate files of a link, removed once it is done or has failed unless they are kept.
+struct Temps {
+    files: Vec<String>,
+    keep: bool,
+}
+
+impl Temps {
+    fn new(keep: bool) -> Self {
+        Temps {
+            files: Vec::new(),
+            keep,
+        }
+    }
+
+    fn add(&mut self, file: String) -> String {
+        self.files.push(file.clone());
+        file
+    }
+}
+
+impl Drop for Temps {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        for file in &self.files {
+            // Files that were never written because an earlier step failed are fine to miss
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
+
+/// Helpers of stdlib.c that are built as instructions rather than called, so LLVM can optimise
+/// them like any other arithmetic. They behave exactly like stdlib.c, values being `uintptr_t`.
+///
+/// `and` has no variant, it is a keyword so programs can't declare or call it.
+#[derive(Clone, Copy, Debug)]
+enum Intrinsic {
+    Inc,
+    Dec,
+    Add,
+    Sub,
+    Less,
+    Greater,
+    Equal,
+    Or,
+    Xor,
+    Not,
+    Bnot,
+    Deref,
+    Store,
+}
+
+impl Intrinsic {
+    /// The intrinsic an external function stands for, if it is declared like in stdlib.c.
+    fn of(stmt: &ast2::ExternFunction) -> Option<Intrinsic> {
+        if stmt.module.is_some() || stmt.variadic {
+            return None;
+        }
+        let (intrinsic, params) = match stmt.name.as_str() {
+            "inc" => (Intrinsic::Inc, 1),
+            "dec" => (Intrinsic::Dec, 1),
+            "add" => (Intrinsic::Add, 2),
+            "sub" => (Intrinsic::Sub, 2),
+            "less" => (Intrinsic::Less, 2),
+            "greater" => (Intrinsic::Greater, 2),
+            "equal" => (Intrinsic::Equal, 2),
+            "or" => (Intrinsic::Or, 2),
+            "xor" => (Intrinsic::Xor, 2),
+            "not" => (Intrinsic::Not, 1),
+            "bnot" => (Intrinsic::Bnot, 1),
+            "deref" => (Intrinsic::Deref, 1),
+            "store" => (Intrinsic::Store, 2),
+            _ => return None,
+        };
+        if stmt.params == params {
+            Some(intrinsic)
+        } else {
+            None
+        }
+    }
+}
+
+/// DWARF metadata built alongside the code, see `CodegenOptions::debug_info`.
+struct DebugInfo<'ctx> {
+    builder: DebugInfoBuilder<'ctx>,
+    unit: DICompileUnit<'ctx>,
+    /// Every value is an unsigned integer as wide as a pointer of the target
+    ty: DIType<'ctx>,
+    optimized: bool,
 }
 
 pub struct Codegen<'ctx> {
     context: &'ctx Context,
     module: Module<'ctx>,
     builder: Builder<'ctx>,
-    i64: IntType<'ctx>,
+    /// Type of every value, an integer as wide as a pointer of the target
+    word: IntType<'ctx>,
+    /// Targeting WebAssembly, which has no indirect branches and calls main by another name
+    wasm: bool,
     func_compile_queue: Vec<ast2::FuncBlock>,
     functions: HashMap<usize, FunctionValue<'ctx>>,
-    /// fn id -> [capture id]
-    function_captures: HashMap<usize, Vec<usize>>,
-    line_lut: GlobalValue<'ctx>,
-    // line -> basic block addr
-    lut_entries: BTreeMap<usize, PointerValue<'ctx>>,
+    /// fn id -> [capture]
+    function_captures: HashMap<usize, Vec<ast2::Identifier>>,
+    /// Name and parameter names of the functions with external linkage, for the header
+    exports: Vec<(String, Vec<String>)>,
+    /// External functions built as instructions, None if intrinsics are off
+    intrinsics: Option<HashMap<usize, Intrinsic>>,
+    /// Abort when a computed goto lands outside of the current function
+    goto_checks: bool,
+    /// See `statement_lines`
+    statement_lines: BTreeSet<usize>,
 
     cur_locals: HashMap<usize, PointerValue<'ctx>>,
     cur_func: Option<FunctionValue<'ctx>>,
-    cur_line_map: HashMap<usize, BasicBlock<'ctx>>,
+    /// Ordered by line, so the destinations of computed gotos are listed in a stable order
+    cur_line_map: BTreeMap<usize, BasicBlock<'ctx>>,
+    /// Targets of the computed gotos of the current function, built by the first one
+    cur_line_table: Option<LineTable<'ctx>>,
+
+    debug: Option<DebugInfo<'ctx>>,
+    cur_subprogram: Option<DISubprogram<'ctx>>,
+    cur_line: usize,
+}
+
+type MainFn = unsafe extern "C" fn(u64, u64) -> u64;
+
+/// How `generate` builds the module, beyond the target.
+struct Settings<'a> {
+    /// File to describe in debug info, None for no debug info
+    debug_source: Option<&'a Path>,
+    optimized: bool,
+    goto_checks: bool,
+    intrinsics: bool,
+    main: bool,
+}
+
+/// Block addresses of the lines of a function, for its computed gotos to jump through.
+///
+/// Only functions with a computed goto have one, taking the address of a block keeps LLVM
+/// from merging or removing it. Lines of other functions are null. WebAssembly has no block
+/// addresses, there computed gotos switch over the same lines instead.
+#[derive(Clone, Copy)]
+struct LineTable<'ctx> {
+    global: GlobalValue<'ctx>,
+    /// Line of the first entry
+    first: usize,
+    len: usize,
 }
 
 impl<'ctx> Codegen<'ctx> {
-    pub fn compile(ast: Vec<ast2::DecoratedStmt>, options: CodegenOptions) -> Result<()> {
+    /// Compile the program, `source` is the file it was read from and is used for debug info.
+    pub fn compile(
+        ast: Vec<ast2::DecoratedStmt>,
+        source: &Path,
+        options: CodegenOptions,
+    ) -> Result<Artifact> {
+        if options.main && options.emit.contains(&Emit::Cdylib) {
+            // It would clash with the main of the program loading the library
+            bail!("--emit=cdylib needs --no-main");
+        }
+        // Shared libraries can be loaded at any address
+        let reloc = if options.pic || options.emit.contains(&Emit::Cdylib) {
+            RelocMode::PIC
+        } else {
+            RelocMode::Default
+        };
+        let machine = create_target_machine(
+            options.target.as_deref(),
+            &options.cpu,
+            &options.target_features,
+            options.optimization,
+            reloc,
+        )?;
         let context = Context::create();
-        let module = context.create_module("beat saber");
+        let settings = Settings {
+            debug_source: if options.debug_info {
+                Some(source)
+            } else {
+                None
+            },
+            optimized: options.optimization != OptLevel::None,
+            goto_checks: options.goto_checks,
+            intrinsics: options.intrinsics,
+            main: options.main,
+        };
+        let codegen = Codegen::generate(&context, ast, &machine, settings)?;
+        codegen.write_object(&machine, options)
+    }
+
+    /// JIT compile the program and run it in-process, returning the result of `main`.
+    pub fn run(ast: Vec<ast2::DecoratedStmt>, options: RunOptions) -> Result<i32> {
+        let machine = create_target_machine(
+            None,
+            "native",
+            &[],
+            options.optimization,
+            RelocMode::Default,
+        )?;
+        let context = Context::create();
+        let settings = Settings {
+            debug_source: None,
+            optimized: false,
+            goto_checks: options.goto_checks,
+            intrinsics: options.intrinsics,
+            main: true,
+        };
+        let codegen = Codegen::generate(&context, ast, &machine, settings)?;
+        codegen.run_jit(options)
+    }
 
-        let line_count = get_line_count(&ast);
-        let line_lut_ty = context
-            .i8_type()
-            .ptr_type(AddressSpace::Generic)
-            .array_type(line_count);
-        let line_lut = module.add_global(line_lut_ty, Some(AddressSpace::Const), "line_lut");
+    fn generate(
+        context: &'ctx Context,
+        ast: Vec<ast2::DecoratedStmt>,
+        machine: &TargetMachine,
+        settings: Settings,
+    ) -> Result<Self> {
+        let module = context.create_module("beat saber");
+        let triple = machine.get_triple();
+        module.set_triple(&triple);
+        let data = machine.get_target_data();
+        module.set_data_layout(&data.get_data_layout());
+        let optimized = settings.optimized;
+        let word = context.ptr_sized_int_type(&data, None);
+        let debug = settings
+            .debug_source
+            .map(|source| Codegen::create_debug_info(context, &module, source, word, optimized))
+            .transpose()?;
 
         let mut codegen = Codegen {
-            context: &context,
+            context,
             module,
             builder: context.create_builder(),
-            i64: context.i64_type(),
+            word,
+            wasm: triple.as_str().to_string_lossy().starts_with("wasm"),
             func_compile_queue: Vec::new(),
             functions: HashMap::new(),
             function_captures: HashMap::new(),
-            line_lut,
-            lut_entries: BTreeMap::new(),
+            exports: Vec::new(),
+            intrinsics: if settings.intrinsics {
+                Some(HashMap::new())
+            } else {
+                None
+            },
+            goto_checks: settings.goto_checks,
+            statement_lines: statement_lines(&ast),
 
             cur_locals: HashMap::new(),
             cur_func: None,
-            cur_line_map: HashMap::new(),
+            cur_line_map: BTreeMap::new(),
+            cur_line_table: None,
+
+            debug,
+            cur_subprogram: None,
+            cur_line: 0,
         };
 
-        codegen.declare_func_children(&ast);
-        codegen.build_main(ast);
+        if settings.main {
+            // Declared first so that a beatsaber function called main can't take the symbol
+            let main = codegen.declare_main();
+            codegen.declare_func_children(&ast, false)?;
+            codegen.build_main(main, ast)?;
+        } else {
+            let stmt = ast
+                .iter()
+                .find(|stmt| !matches!(stmt, ast2::DecoratedStmt::Callable(_)));
+            if let Some(stmt) = stmt {
+                bail!(
+                    "line {}: statements outside of functions need a main function",
+                    stmt.line_number()
+                );
+            }
+            codegen.declare_func_children(&ast, true)?;
+            for stmt in ast {
+                codegen.build_stmt(stmt, None)?;
+            }
+            codegen.build_queued_functions()?;
+        }
+        if let Some(debug) = &codegen.debug {
+            debug.builder.finalize();
+        }
+        codegen.set_target_attributes(machine);
+
+        Ok(codegen)
+    }
 
-        let mut lut_data = Vec::new();
-        for (&line, &ptr) in &codegen.lut_entries {
-            while lut_data.len() < line {
-                lut_data.push(ptr);
+    fn create_debug_info(
+        context: &'ctx Context,
+        module: &Module<'ctx>,
+        source: &Path,
+        word: IntType<'ctx>,
+        optimized: bool,
+    ) -> Result<DebugInfo<'ctx>> {
+        let directory = std::env::current_dir()?;
+        let (builder, unit) = module.create_debug_info_builder(
+            true,
+            DWARFSourceLanguage::C,
+            &source.to_string_lossy(),
+            &directory.to_string_lossy(),
+            "bsc",
+            optimized,
+            "",
+            0,
+            "",
+            DWARFEmissionKind::Full,
+            0,
+            false,
+            false,
+            "",
+            "",
+        );
+        let debug_version = context.i32_type().const_int(3, false);
+        module.add_basic_value_flag("Debug Info Version", FlagBehavior::Warning, debug_version);
+        let dwarf_version = context.i32_type().const_int(4, false);
+        module.add_basic_value_flag("Dwarf Version", FlagBehavior::Warning, dwarf_version);
+
+        // DW_ATE_unsigned
+        let bits = word.get_bit_width();
+        let ty = builder
+            .create_basic_type(&format!("u{}", bits), bits as u64, 0x08, DIFlags::ZERO)
+            .map_err(Error::msg)?
+            .as_type();
+        Ok(DebugInfo {
+            builder,
+            unit,
+            ty,
+            optimized,
+        })
+    }
+
+    /// Record the CPU and features of `machine` on the defined functions, like clang does, so
+    /// the IR builds the same when compiled on its own.
+    fn set_target_attributes(&self, machine: &TargetMachine) {
+        let cpu = machine.get_cpu().to_string_lossy().into_owned();
+        let features = machine.get_feature_string().to_string_lossy();
+        for function in self.module.get_functions() {
+            if function.count_basic_blocks() == 0 {
+                continue;
+            }
+            let attribute = self.context.create_string_attribute("target-cpu", &cpu);
+            function.add_attribute(AttributeLoc::Function, attribute);
+            if !features.is_empty() {
+                let attribute = self
+                    .context
+                    .create_string_attribute("target-features", &features);
+                function.add_attribute(AttributeLoc::Function, attribute);
             }
         }
-        let elem_ty = context.i8_type().ptr_type(AddressSpace::Generic);
-        line_lut.set_initializer(&elem_ty.const_array(&lut_data));
+    }
+
+    /// Attach a subprogram to the function about to be built, `params` includes captures.
+    fn debug_function(
+        &mut self,
+        fn_val: FunctionValue<'ctx>,
+        name: &str,
+        line: usize,
+        params: usize,
+    ) {
+        let debug = match &self.debug {
+            Some(debug) => debug,
+            None => return,
+        };
+        let file = debug.unit.get_file();
+        let fn_ty = debug.builder.create_subroutine_type(
+            file,
+            Some(debug.ty),
+            &vec![debug.ty; params],
+            DIFlags::ZERO,
+        );
+        let subprogram = debug.builder.create_function(
+            debug.unit.as_debug_info_scope(),
+            name,
+            None,
+            file,
+            line as u32,
+            fn_ty,
+            fn_val.get_linkage() == Linkage::Internal,
+            true,
+            line as u32,
+            DIFlags::ZERO,
+            debug.optimized,
+        );
+        fn_val.set_subprogram(subprogram);
+        self.cur_subprogram = Some(subprogram);
+        self.set_debug_line(line);
+    }
 
-        codegen.write_object(options)
+    /// Attribute the instructions built from now on to a source line.
+    fn set_debug_line(&mut self, line: usize) {
+        self.cur_line = line;
+        if let (Some(debug), Some(subprogram)) = (&self.debug, self.cur_subprogram) {
+            let location = debug.builder.create_debug_location(
+                self.context,
+                line as u32,
+                0,
+                subprogram.as_debug_info_scope(),
+                None,
+            );
+            self.builder
+                .set_current_debug_location(self.context, location);
+        }
     }
 
-    fn declare_func_children(&mut self, stmts: &[ast2::DecoratedStmt]) {
+    /// Describe the variable in the alloca `ptr`, `arg` is its position if it is a parameter.
+    fn debug_variable(&self, ptr: PointerValue<'ctx>, name: &str, arg: Option<usize>) {
+        let (debug, subprogram) = match (&self.debug, self.cur_subprogram) {
+            (Some(debug), Some(subprogram)) => (debug, subprogram),
+            _ => return,
+        };
+        let scope = subprogram.as_debug_info_scope();
+        let file = debug.unit.get_file();
+        let line = self.cur_line as u32;
+        let var = match arg {
+            Some(arg) => debug.builder.create_parameter_variable(
+                scope,
+                name,
+                arg as u32 + 1,
+                file,
+                line,
+                debug.ty,
+                true,
+                DIFlags::ZERO,
+            ),
+            None => debug.builder.create_auto_variable(
+                scope,
+                name,
+                file,
+                line,
+                debug.ty,
+                true,
+                DIFlags::ZERO,
+                0,
+            ),
+        };
+        let location = debug
+            .builder
+            .create_debug_location(self.context, line, 0, scope, None);
+        // Declare right after the alloca, allocas are always in the entry block
+        let alloca = ptr.as_instruction().unwrap();
+        match alloca.get_next_instruction() {
+            Some(next) => {
+                debug.builder.insert_declare_before_instruction(
+                    ptr,
+                    Some(var),
+                    None,
+                    location,
+                    next,
+                );
+            }
+            None => {
+                let block = alloca.get_parent().unwrap();
+                debug
+                    .builder
+                    .insert_declare_at_end(ptr, Some(var), None, location, block);
+            }
+        }
+    }
+
+    /// Declare the functions in `stmts` and the ones nested in them, `export` gives those in
+    /// `stmts` external linkage, as does `here for all`.
+    fn declare_func_children(&mut self, stmts: &[ast2::DecoratedStmt], export: bool) -> Result<()> {
         for stmt in stmts {
             match stmt {
                 ast2::DecoratedStmt::Callable(ast2::Callable::ExternFunction(stmt)) => {
-                    self.function_captures.insert(stmt.ident.id, Vec::new());
-                    let mut param_types = vec![BasicTypeEnum::IntType(self.i64)];
-                    if stmt.two_param {
-                        param_types.push(BasicTypeEnum::IntType(self.i64));
+                    if let Some(intrinsics) = &mut self.intrinsics {
+                        if let Some(intrinsic) = Intrinsic::of(stmt) {
+                            // Never called, so stdlib.c isn't linked just for it
+                            intrinsics.insert(stmt.ident.id, intrinsic);
+                            continue;
+                        }
                     }
-                    let fn_type = self.i64.fn_type(&param_types, false);
+                    self.function_captures.insert(stmt.ident.id, Vec::new());
+                    let param_types = vec![BasicTypeEnum::IntType(self.word); stmt.params];
+                    let fn_type = self.word.fn_type(&param_types, stmt.variadic);
                     let fn_val =
                         self.module
                             .add_function(&stmt.name, fn_type, Some(Linkage::External));
                     self.functions.insert(stmt.ident.id, fn_val);
                 }
                 ast2::DecoratedStmt::Callable(ast2::Callable::FuncBlock(stmt)) => {
-                    let mut param_types = vec![BasicTypeEnum::IntType(self.i64)];
-                    let mut params = vec![stmt.decl.p1.id];
-                    if let Some(p2) = stmt.decl.p2 {
-                        params.push(p2.id);
-                        param_types.push(BasicTypeEnum::IntType(self.i64));
-                    }
+                    let params: Vec<_> = stmt.decl.params.iter().map(|param| param.id).collect();
+                    let mut param_types = vec![BasicTypeEnum::IntType(self.word); params.len()];
 
-                    let captures = find_captures(&stmt.block, &params);
+                    let captures = ast2::find_captures(&stmt.block, &params);
                     for _ in &captures {
-                        param_types.push(BasicTypeEnum::IntType(self.i64));
+                        param_types.push(BasicTypeEnum::IntType(self.word));
                     }
+
+                    let name = &stmt.decl.id.name;
+                    let export = export || stmt.decl.export.is_some();
+                    let linkage = if export {
+                        if let Some(capture) = captures.first() {
+                            bail!("{} can't be exported, it captures {}", name, capture.name);
+                        }
+                        Linkage::External
+                    } else {
+                        Linkage::Internal
+                    };
                     self.function_captures.insert(stmt.decl.id.id, captures);
 
-                    let fn_type = self.i64.fn_type(&param_types, false);
-                    let fn_val = self.module.add_function(
-                        &stmt.decl.id.id.to_string(),
-                        fn_type,
-                        Some(Linkage::Internal),
-                    );
+                    let fn_type = self.word.fn_type(&param_types, false);
+                    let fn_val = self.module.add_function(name, fn_type, Some(linkage));
+                    // LLVM renames functions whose name is taken
+                    if export && fn_val.get_name().to_bytes() != name.as_bytes() {
+                        bail!("{} can't be exported, the name is already taken", name);
+                    }
+                    if export {
+                        let params = stmt.decl.params.iter().map(|p| p.name.clone()).collect();
+                        self.exports.push((name.clone(), params));
+                    }
                     self.functions.insert(stmt.decl.id.id, fn_val);
 
-                    self.declare_func_children(&stmt.block);
+                    self.declare_func_children(&stmt.block, false)?;
                 }
                 _ => {}
             }
         }
+        Ok(())
     }
 
-    fn get_local(&mut self, id: usize, create: bool) -> PointerValue<'ctx> {
-        if let Some(local) = self.cur_locals.get(&id) {
-            *local
+    fn get_local(&mut self, ident: &ast2::Identifier, create: bool) -> Result<PointerValue<'ctx>> {
+        if let Some(local) = self.cur_locals.get(&ident.id) {
+            Ok(*local)
         } else if create {
             let builder = self.context.create_builder();
 
@@ -219,33 +911,49 @@ impl<'ctx> Codegen<'ctx> {
                 None => builder.position_at_end(entry),
             }
 
-            let ptr = builder.build_alloca(self.i64, "");
-            self.cur_locals.insert(id, ptr);
-            ptr
+            let ptr = builder.build_alloca(self.word, &ident.name);
+            self.cur_locals.insert(ident.id, ptr);
+            self.debug_variable(ptr, &ident.name, None);
+            Ok(ptr)
         } else {
-            panic!("Could not find local {}", id);
+            bail!("could not find local {}", ident.name);
         }
     }
 
-    fn build_main(&mut self, body: Vec<ast2::DecoratedStmt>) {
-        let param_ty = BasicTypeEnum::IntType(self.i64);
-        let fn_type = self.i64.fn_type(&[param_ty, param_ty], false);
-        let fn_val = self
-            .module
-            .add_function("main", fn_type, Some(Linkage::External));
+    fn declare_main(&self) -> FunctionValue<'ctx> {
+        let param_ty = BasicTypeEnum::IntType(self.word);
+        let fn_type = self.word.fn_type(&[param_ty, param_ty], false);
+        // wasi-libc calls the main of C, taking argc and argv, by this name
+        let name = if self.wasm {
+            "__main_argc_argv"
+        } else {
+            "main"
+        };
+        self.module
+            .add_function(name, fn_type, Some(Linkage::External))
+    }
+
+    fn build_main(
+        &mut self,
+        fn_val: FunctionValue<'ctx>,
+        body: Vec<ast2::DecoratedStmt>,
+    ) -> Result<()> {
         self.cur_func = Some(fn_val);
 
         let params = fn_val.get_params();
         let entry = self.context.append_basic_block(fn_val, "");
         self.builder.position_at_end(entry);
+        self.debug_function(fn_val, "main", 1, 2);
 
-        let p1alloca = self.builder.build_alloca(self.i64, "argc");
+        let p1alloca = self.builder.build_alloca(self.word, "argc");
         self.builder.build_store(p1alloca, params[0]);
-        self.cur_locals.insert(ast2::ARGC_IDENT.id, p1alloca);
+        self.cur_locals.insert(ast2::ARGC_ID, p1alloca);
+        self.debug_variable(p1alloca, "argc", Some(0));
 
-        let p2alloca = self.builder.build_alloca(self.i64, "argv");
+        let p2alloca = self.builder.build_alloca(self.word, "argv");
         self.builder.build_store(p2alloca, params[1]);
-        self.cur_locals.insert(ast2::ARGV_IDENT.id, p2alloca);
+        self.cur_locals.insert(ast2::ARGV_ID, p2alloca);
+        self.debug_variable(p2alloca, "argv", Some(1));
 
         for stmt in &body {
             let line = stmt.line_number();
@@ -254,50 +962,33 @@ impl<'ctx> Codegen<'ctx> {
             }
             let block = self.context.append_basic_block(fn_val, "");
             self.cur_line_map.insert(line, block);
-            let addr = unsafe {
-                let f = mem::transmute::<_, _>(self.cur_func.unwrap());
-                let bb = mem::transmute::<_, _>(block);
-                let val = llvm_sys::core::LLVMBlockAddress(f, bb);
-                mem::transmute::<_, PointerValue>(val)
-            };
-            self.lut_entries.insert(line, addr);
         }
 
         self.builder
             .build_unconditional_branch(entry.get_next_basic_block().unwrap());
 
         for stmt in body {
-            self.build_stmt(stmt, None);
+            self.build_stmt(stmt, None)?;
         }
 
-        while !self.func_compile_queue.is_empty() {
-            let func = self.func_compile_queue.pop().unwrap();
-            let p1 = func.decl.p1.id;
-            let p2 = func.decl.p2.map(|id| id.id);
-            self.build_func(func.block, func.decl.id.id, p1, p2);
-        }
+        self.build_queued_functions()?;
 
-        if let Some(func) = self.cur_func {
-            if func.verify(true) {
-            } else {
-                unsafe {
-                    func.delete();
-                }
+        self.verify_cur_func()
+    }
 
-                panic!("stack bad");
-            }
+    fn build_queued_functions(&mut self) -> Result<()> {
+        while let Some(func) = self.func_compile_queue.pop() {
+            self.build_func(func)?;
         }
+        Ok(())
     }
 
-    fn build_func(
-        &mut self,
-        body: Vec<ast2::DecoratedStmt>,
-        id: usize,
-        p1: usize,
-        p2: Option<usize>,
-    ) {
+    fn build_func(&mut self, func: ast2::FuncBlock) -> Result<()> {
+        let ast2::FuncBlock { decl, block: body } = func;
+        let id = decl.id.id;
         self.cur_locals.clear();
         self.cur_line_map.clear();
+        self.cur_line_table = None;
         let fn_val = *self.functions.get(&id).unwrap();
         self.cur_func = Some(fn_val);
 
@@ -305,21 +996,21 @@ impl<'ctx> Codegen<'ctx> {
 
         let entry = self.context.append_basic_block(fn_val, "");
         self.builder.position_at_end(entry);
-        let p1alloca = self.builder.build_alloca(self.i64, "");
-        self.builder.build_store(p1alloca, params[0]);
-        self.cur_locals.insert(p1, p1alloca);
+        self.debug_function(fn_val, &decl.id.name, decl.line, params.len());
 
-        if let Some(p2) = p2 {
-            let p2alloca = self.builder.build_alloca(self.i64, "");
-            self.builder.build_store(p2alloca, params[1]);
-            self.cur_locals.insert(p2, p2alloca);
+        for (i, param) in decl.params.iter().enumerate() {
+            let alloca = self.builder.build_alloca(self.word, &param.name);
+            self.builder.build_store(alloca, params[i]);
+            self.cur_locals.insert(param.id, alloca);
+            self.debug_variable(alloca, &param.name, Some(i));
         }
 
-        let capture_offset = p2.is_some() as usize + 1;
-        for (i, &capture) in self.function_captures[&id].iter().enumerate() {
-            let alloca = self.builder.build_alloca(self.i64, "");
+        let capture_offset = decl.params.len();
+        for (i, capture) in self.function_captures[&id].iter().enumerate() {
+            let alloca = self.builder.build_alloca(self.word, &capture.name);
             self.builder.build_store(alloca, params[capture_offset + i]);
-            self.cur_locals.insert(capture, alloca);
+            self.cur_locals.insert(capture.id, alloca);
+            self.debug_variable(alloca, &capture.name, Some(capture_offset + i));
         }
 
         for stmt in &body {
@@ -329,70 +1020,230 @@ impl<'ctx> Codegen<'ctx> {
             }
             let block = self.context.append_basic_block(fn_val, "");
             self.cur_line_map.insert(line, block);
-            let addr = unsafe {
-                let f = mem::transmute::<_, _>(self.cur_func.unwrap());
-                let bb = mem::transmute::<_, _>(block);
-                let val = llvm_sys::core::LLVMBlockAddress(f, bb);
-                mem::transmute::<_, PointerValue>(val)
-            };
-            self.lut_entries.insert(line, addr);
         }
 
         self.builder
             .build_unconditional_branch(entry.get_next_basic_block().unwrap());
 
         for stmt in body {
-            self.build_stmt(stmt, None);
+            self.build_stmt(stmt, None)?;
         }
 
+        self.verify_cur_func()
+    }
+
+    /// Continue the goto in a new block if `ok` holds, call `bs_goto_failed` otherwise.
+    fn build_goto_check(&mut self, ok: IntValue<'ctx>, target: IntValue<'ctx>, line: usize) {
+        let block = self.builder.get_insert_block().unwrap();
+        // Inserted right after the goto so they don't become the fallthrough of another line
+        let ok_block = self.context.insert_basic_block_after(block, "");
+        let fail_block = self.context.insert_basic_block_after(block, "");
+        self.builder
+            .build_conditional_branch(ok, ok_block, fail_block);
+
+        self.builder.position_at_end(fail_block);
+        self.build_goto_failed(target, line);
+
+        self.builder.position_at_end(ok_block);
+    }
+
+    /// Call `bs_goto_failed`, which aborts.
+    fn build_goto_failed(&mut self, target: IntValue<'ctx>, line: usize) {
+        let hook = self
+            .module
+            .get_function("bs_goto_failed")
+            .unwrap_or_else(|| {
+                let param_ty = BasicTypeEnum::IntType(self.word);
+                let fn_type = self
+                    .context
+                    .void_type()
+                    .fn_type(&[param_ty, param_ty], false);
+                self.module
+                    .add_function("bs_goto_failed", fn_type, Some(Linkage::External))
+            });
+        let line = self.word.const_int(line as u64, false);
+        self.builder.build_call(
+            hook,
+            &[
+                BasicValueEnum::IntValue(target),
+                BasicValueEnum::IntValue(line),
+            ],
+            "",
+        );
+        self.builder.build_unreachable();
+    }
+
+    /// Where a computed goto in the current function lands for each line from the first one
+    /// returned, None for lines of other functions.
+    fn goto_destinations(&self) -> (usize, Vec<Option<BasicBlock<'ctx>>>) {
+        let lines = &self.statement_lines;
+        let own_first = *self.cur_line_map.keys().next().unwrap();
+        let last = *self.cur_line_map.keys().next_back().unwrap();
+        // The lines between the previous statement and the function lead into it
+        let first = lines
+            .range(..own_first)
+            .next_back()
+            .map_or(1, |line| line + 1);
+        let destinations = (first..=last)
+            .map(|line| {
+                // A line without a statement continues with the next one
+                let next = lines.range(line..).next();
+                next.and_then(|next| self.cur_line_map.get(next)).copied()
+            })
+            .collect();
+        (first, destinations)
+    }
+
+    /// The line table of the current function, built the first time a computed goto needs it.
+    fn line_table(&mut self) -> LineTable<'ctx> {
+        if let Some(table) = self.cur_line_table {
+            return table;
+        }
+        let fn_val = self.cur_func.unwrap();
+        let (first, destinations) = self.goto_destinations();
+        let elem_ty = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let entries: Vec<_> = destinations
+            .into_iter()
+            .map(|block| match block {
+                Some(block) => self.block_address(fn_val, block),
+                None => elem_ty.const_null(),
+            })
+            .collect();
+
+        let ty = elem_ty.array_type(entries.len() as u32);
+        let name = format!("{}.lines", fn_val.get_name().to_string_lossy());
+        let global = self.module.add_global(ty, Some(AddressSpace::Const), &name);
+        global.set_initializer(&elem_ty.const_array(&entries));
+        global.set_constant(true);
+        global.set_linkage(Linkage::Private);
+        let table = LineTable {
+            global,
+            first,
+            len: entries.len(),
+        };
+        self.cur_line_table = Some(table);
+        table
+    }
+
+    fn block_address(
+        &self,
+        fn_val: FunctionValue<'ctx>,
+        block: BasicBlock<'ctx>,
+    ) -> PointerValue<'ctx> {
+        unsafe {
+            let f = mem::transmute::<_, _>(fn_val);
+            let bb = mem::transmute::<_, _>(block);
+            let val = llvm_sys::core::LLVMBlockAddress(f, bb);
+            mem::transmute::<_, PointerValue>(val)
+        }
+    }
+
+    fn verify_cur_func(&self) -> Result<()> {
         if let Some(func) = self.cur_func {
-            if func.verify(true) {
-            } else {
+            if !func.verify(false) {
+                let name = func.get_name().to_string_lossy().into_owned();
                 unsafe {
                     func.delete();
                 }
 
-                panic!("stack bad");
+                bail!("generated invalid code for function {}", name);
             }
         }
+        Ok(())
     }
 
-    fn build_expr(&mut self, expr: ast2::DecoratedExpr) -> IntValue<'ctx> {
+    fn build_expr(&mut self, expr: ast2::DecoratedExpr) -> Result<IntValue<'ctx>> {
         match expr {
             ast2::DecoratedExpr::CallExpr(expr) => {
+                let intrinsic = self
+                    .intrinsics
+                    .as_ref()
+                    .and_then(|intrinsics| intrinsics.get(&expr.function.id).copied());
+                if let Some(intrinsic) = intrinsic {
+                    let mut args = Vec::new();
+                    for arg in expr.args {
+                        args.push(self.build_expr(arg)?);
+                    }
+                    return Ok(self.build_intrinsic(intrinsic, &args));
+                }
                 let fn_val = self.functions[&expr.function.id];
                 let mut args = Vec::new();
-                let p1 = self.build_expr(*expr.p1);
-                args.push(BasicValueEnum::IntValue(p1));
-                if let Some(p2) = expr.p2 {
-                    args.push(BasicValueEnum::IntValue(self.build_expr(*p2)));
+                for arg in expr.args {
+                    args.push(BasicValueEnum::IntValue(self.build_expr(arg)?));
                 }
-                for &capture in &self.function_captures[&expr.function.id].clone() {
-                    let ptr = self.get_local(capture, false);
+                for capture in &self.function_captures[&expr.function.id].clone() {
+                    let ptr = self.get_local(capture, false)?;
                     args.push(self.builder.build_load(ptr, ""));
                 }
-                self.builder
+                Ok(self
+                    .builder
                     .build_call(fn_val, &args, "")
                     .try_as_basic_value()
                     .left()
                     .unwrap()
-                    .into_int_value()
+                    .into_int_value())
             }
             ast2::DecoratedExpr::Identifier(expr) => {
-                let ptr = self.get_local(expr.id, false);
-                self.builder.build_load(ptr, "").into_int_value()
+                let ptr = self.get_local(&expr, false)?;
+                Ok(self.builder.build_load(ptr, "").into_int_value())
+            }
+        }
+    }
+
+    fn build_intrinsic(&self, intrinsic: Intrinsic, args: &[IntValue<'ctx>]) -> IntValue<'ctx> {
+        let one = self.word.const_int(1, false);
+        let compare = |predicate, lhs: IntValue<'ctx>, rhs: IntValue<'ctx>| {
+            let cmp = self.builder.build_int_compare(predicate, lhs, rhs, "");
+            self.builder.build_int_z_extend(cmp, self.word, "")
+        };
+        let ptr = |addr: IntValue<'ctx>| {
+            let ty = self.word.ptr_type(AddressSpace::Generic);
+            self.builder.build_int_to_ptr(addr, ty, "")
+        };
+        match intrinsic {
+            Intrinsic::Inc => self.builder.build_int_add(args[0], one, ""),
+            Intrinsic::Dec => self.builder.build_int_sub(args[0], one, ""),
+            Intrinsic::Add => self.builder.build_int_add(args[0], args[1], ""),
+            Intrinsic::Sub => self.builder.build_int_sub(args[0], args[1], ""),
+            Intrinsic::Less => compare(IntPredicate::ULT, args[0], args[1]),
+            Intrinsic::Greater => compare(IntPredicate::UGT, args[0], args[1]),
+            Intrinsic::Equal => compare(IntPredicate::EQ, args[0], args[1]),
+            Intrinsic::Or => self.builder.build_or(args[0], args[1], ""),
+            Intrinsic::Xor => self.builder.build_xor(args[0], args[1], ""),
+            Intrinsic::Not => compare(IntPredicate::EQ, args[0], self.word.const_zero()),
+            Intrinsic::Bnot => self.builder.build_not(args[0], ""),
+            Intrinsic::Deref => self.builder.build_load(ptr(args[0]), "").into_int_value(),
+            Intrinsic::Store => {
+                self.builder.build_store(ptr(args[0]), args[1]);
+                // Returns void in stdlib.c
+                self.word.const_zero()
             }
         }
     }
 
-    fn build_stmt(&mut self, stmt: ast2::DecoratedStmt, continue_block: Option<BasicBlock>) {
+    fn build_stmt(
+        &mut self,
+        stmt: ast2::DecoratedStmt,
+        continue_block: Option<BasicBlock<'ctx>>,
+    ) -> Result<()> {
         if let ast2::DecoratedStmt::Callable(stmt) = stmt {
             if let ast2::Callable::FuncBlock(stmt) = stmt {
                 // println!("Pushing function block to compile queue {:?}", stmt);
                 self.func_compile_queue.push(stmt);
             }
-            return;
+            return Ok(());
         }
+        let block = self.cur_line_map[&stmt.line_number()];
+        self.build_stmt_in(stmt, block, continue_block)
+    }
+
+    /// Build a statement at the end of `block`.
+    fn build_stmt_in(
+        &mut self,
+        stmt: ast2::DecoratedStmt,
+        block: BasicBlock<'ctx>,
+        continue_block: Option<BasicBlock<'ctx>>,
+    ) -> Result<()> {
         let is_terminator = matches!(
             stmt,
             ast2::DecoratedStmt::ReturnStmt(_)
@@ -401,58 +1252,93 @@ impl<'ctx> Codegen<'ctx> {
         );
 
         let line = stmt.line_number();
-        let block = self.cur_line_map[&line];
         self.builder.position_at_end(block);
+        self.set_debug_line(line);
         match stmt {
             ast2::DecoratedStmt::LoadLiteralNumber(stmt) => {
-                let ptr = self.get_local(stmt.ident.id, true);
-                let val = self.i64.const_int(stmt.value as u64, false);
+                let ptr = self.get_local(&stmt.ident, true)?;
+                let val = self.word.const_int(stmt.value as u64, false);
                 self.builder.build_store(ptr, val);
             }
             ast2::DecoratedStmt::Conditional(stmt) => {
-                let cond = self.build_expr(ast2::DecoratedExpr::Identifier(stmt.condition));
-                let z = self.i64.const_zero();
+                let cond = self.build_expr(ast2::DecoratedExpr::Identifier(stmt.condition))?;
+                let z = self.word.const_zero();
                 let cond_c = self
                     .builder
                     .build_int_compare(inkwell::IntPredicate::NE, cond, z, "");
                 let else_block = block.get_next_basic_block().unwrap();
                 let then_block = self.context.append_basic_block(self.cur_func.unwrap(), "");
-                // shitty hack to get it to write this statement to the then block
-                self.cur_line_map.insert(line, then_block);
-                self.build_stmt(*stmt.success, Some(else_block));
-                self.cur_line_map.insert(line, block);
+                self.build_stmt_in(*stmt.success, then_block, Some(else_block))?;
 
                 self.builder.position_at_end(block);
                 self.builder
                     .build_conditional_branch(cond_c, then_block, else_block);
             }
             ast2::DecoratedStmt::Assignment(stmt) => {
-                let val = self.build_expr(stmt.value);
-                if let Some(id) = stmt.name {
-                    let ptr = self.get_local(id.id, true);
+                let val = self.build_expr(stmt.value)?;
+                if let Some(id) = &stmt.name {
+                    let ptr = self.get_local(id, true)?;
                     self.builder.build_store(ptr, val);
                 }
             }
             ast2::DecoratedStmt::ReturnStmt(stmt) => {
-                let val = self.build_expr(stmt.expr);
+                let val = self.build_expr(stmt.expr)?;
                 self.builder.build_return(Some(&val));
             }
+            ast2::DecoratedStmt::GotoStmt(ast2::GotoStmt {
+                dest: Some(dest), ..
+            }) => {
+                // Checked by goto::resolve, the line is in this function
+                self.builder
+                    .build_unconditional_branch(self.cur_line_map[&dest]);
+            }
+            ast2::DecoratedStmt::GotoStmt(stmt) if self.wasm => {
+                let target = self.build_expr(stmt.target)?;
+                let (first, destinations) = self.goto_destinations();
+                let cases: Vec<_> = destinations
+                    .into_iter()
+                    .zip(first..)
+                    .filter_map(|(block, line)| {
+                        Some((self.word.const_int(line as u64, false), block?))
+                    })
+                    .collect();
+                let block = self.builder.get_insert_block().unwrap();
+                let invalid = self.context.insert_basic_block_after(block, "");
+                self.builder.build_switch(target, invalid, &cases);
+                self.builder.position_at_end(invalid);
+                if self.goto_checks {
+                    self.build_goto_failed(target, line);
+                } else {
+                    self.builder.build_unreachable();
+                }
+            }
             ast2::DecoratedStmt::GotoStmt(stmt) => {
-                let val = self.build_expr(stmt.target);
-                // subtract line num by one
-                let val = self
-                    .builder
-                    .build_int_sub(val, self.i64.const_int(1, false), "");
-                let lut = self.line_lut.as_pointer_value();
-
-                let lut_elem = unsafe {
-                    let z = self.i64.const_zero();
-                    self.builder.build_in_bounds_gep(lut, &[z, val], "")
+                let target = self.build_expr(stmt.target)?;
+                let table = self.line_table();
+                let first = self.word.const_int(table.first as u64, false);
+                let index = self.builder.build_int_sub(target, first, "");
+                if self.goto_checks {
+                    // Unsigned, so lines before the table wrap around and are out of bounds too
+                    let len = self.word.const_int(table.len as u64, false);
+                    let in_bounds =
+                        self.builder
+                            .build_int_compare(IntPredicate::ULT, index, len, "");
+                    self.build_goto_check(in_bounds, target, line);
+                }
+                let entry = unsafe {
+                    let z = self.word.const_zero();
+                    let ptr = table.global.as_pointer_value();
+                    self.builder.build_in_bounds_gep(ptr, &[z, index], "")
                 };
-                let target = self.builder.build_load(lut_elem, "").into_pointer_value();
+                let addr = self.builder.build_load(entry, "").into_pointer_value();
+                if self.goto_checks {
+                    // Lines of other functions are null
+                    let in_func = self.builder.build_is_not_null(addr, "");
+                    self.build_goto_check(in_func, target, line);
+                }
 
                 let destinations: Vec<BasicBlock> = self.cur_line_map.values().cloned().collect();
-                self.builder.build_indirect_branch(target, &destinations);
+                self.builder.build_indirect_branch(addr, &destinations);
             }
             ast2::DecoratedStmt::LoadLiteralString(stmt) => {
                 let elem_type = self.context.i8_type();
@@ -468,10 +1354,12 @@ impl<'ctx> Codegen<'ctx> {
                 global.set_initializer(&val);
                 let ptr = global.as_pointer_value();
 
-                let local = self.get_local(stmt.ident.id, true);
-                let int = self.builder.build_ptr_to_int(ptr, self.i64, "");
+                let local = self.get_local(&stmt.ident, true)?;
+                let int = self.builder.build_ptr_to_int(ptr, self.word, "");
                 self.builder.build_store(local, int);
             }
+            // Only marks the line, control falls through to the next statement
+            ast2::DecoratedStmt::Label(_) => {}
             a => unreachable!("{:?}", a),
         }
 
@@ -481,109 +1369,680 @@ impl<'ctx> Codegen<'ctx> {
             } else if let Some(next_block) = block.get_next_basic_block() {
                 self.builder.build_unconditional_branch(next_block);
             } else {
-                let val = self.i64.const_int(0, false);
+                let val = self.word.const_int(0, false);
                 self.builder.build_return(Some(&val));
             }
         }
+        Ok(())
     }
 
-    fn write_object(&self, options: CodegenOptions) -> Result<()> {
-        Target::initialize_all(&InitializationConfig::default());
-
-        let triple = if let Some(triple) = &options.target {
-            TargetTriple::create(triple)
-        } else {
-            TargetMachine::get_default_triple()
-        };
-        let target = Target::from_triple(&triple).unwrap();
-        let (cpu, features) = if options.target.is_some() {
-            // TODO: cli option for cpu and features
-            (String::new(), String::new())
-        } else {
-            let cpu = TargetMachine::get_host_cpu_name().to_string();
-            let features = TargetMachine::get_host_cpu_features().to_string();
-            (cpu, features)
-        };
-
-        let reloc = if options.pic {
-            RelocMode::PIC
-        } else {
-            RelocMode::Default
+    fn write_object(
+        &self,
+        target_machine: &TargetMachine,
+        options: CodegenOptions,
+    ) -> Result<Artifact> {
+        let triple = target_machine.get_triple();
+        let mut artifact = Artifact {
+            outputs: Vec::new(),
+            warnings: Vec::new(),
         };
-        let model = CodeModel::Default;
-        let opt = options.optimization;
+        for &emit in &options.emit {
+            // With a single output kind the path is used as is, otherwise it's used as a stem
+            let path = if options.emit.len() == 1 || emit.linked() {
+                options.output.to_owned()
+            } else {
+                options.output.with_extension(emit.extension())
+            };
+            match emit {
+                Emit::LlvmIr => self
+                    .module
+                    .print_to_file(&path)
+                    .map_err(|e| anyhow!("failed to write {}: {}", path.display(), e))?,
+                Emit::Header => std::fs::write(&path, self.header(&path))
+                    .map_err(|e| anyhow!("failed to write {}: {}", path.display(), e))?,
+                Emit::LlvmBc => {
+                    if !self.module.write_bitcode_to_path(&path) {
+                        bail!("failed to write {}", path.display());
+                    }
+                }
+                Emit::Asm | Emit::Obj => {
+                    let file_type = if emit == Emit::Asm {
+                        FileType::Assembly
+                    } else {
+                        FileType::Object
+                    };
+                    target_machine
+                        .write_to_file(&self.module, file_type, &path)
+                        .map_err(|e| anyhow!("failed to write {}: {}", path.display(), e))?;
+                }
+                Emit::Exe | Emit::StaticLib | Emit::Cdylib => {
+                    self.link(target_machine, &triple, &options, emit, &path)?
+                }
+            }
+            artifact.outputs.push((emit, path));
+        }
 
-        let target_machine = target
-            .create_target_machine(&triple, &cpu, &features, opt, reloc, model)
-            .unwrap();
+        Ok(artifact)
+    }
 
-        // self.module.print_to_stderr();
-        let tmp_out = format!("{}.tmp", options.output.display());
+    /// Link the program and the C files it needs into an executable or a library.
+    ///
+    /// A static library bundles everything, stdlib.c included, except the libraries named by
+    /// `but is in`, which whoever links it has to provide.
+    fn link(
+        &self,
+        target_machine: &TargetMachine,
+        triple: &TargetTriple,
+        options: &CodegenOptions,
+        emit: Emit,
+        output: &Path,
+    ) -> Result<()> {
+        let opt = options.optimization as u32;
+        let mut temps = Temps::new(options.keep_temps);
+        let tmp_out = temps.add(format!("{}.tmp", output.display()));
         target_machine
             .write_to_file(&self.module, FileType::Object, Path::new(&tmp_out))
-            .unwrap();
+            .map_err(|e| anyhow!("failed to write {}: {}", tmp_out, e))?;
 
-        let includes = options
-            .include_c
-            .iter()
-            .map(|c| self.compile_c(c, &triple, opt as u32))
-            .collect::<Result<Vec<_>, _>>()?;
+        let mut includes = Vec::new();
+        for c in &options.include_c {
+            includes.push(temps.add(self.compile_c(c, triple, opt)?));
+        }
+        if self.module.get_function("bs_goto_failed").is_some() {
+            let hooks = temps.add(format!("{}.runtime.c", output.display()));
+            std::fs::write(&hooks, runtime::HOOKS)?;
+            includes.push(temps.add(self.compile_c(&hooks, triple, opt)?));
+        }
+        let uses_std = options.std && self.uses_std();
+
+        if emit == Emit::StaticLib {
+            let mut objects = vec![tmp_out];
+            objects.extend(includes);
+            if uses_std {
+                objects.push(self.compile_std(triple, opt, output, &mut temps)?);
+            }
+            return archive(triple, opt, &output.display().to_string(), &objects);
+        }
+
+        let stdlib = if uses_std {
+            Some(self.archive_std(triple, opt, output, &mut temps)?)
+        } else {
+            None
+        };
 
-        let cc = cc::Build::new()
-            .target(triple.as_str().to_str().unwrap())
-            .host(env!("HOST"))
-            .opt_level(opt as u32)
-            .cargo_metadata(false)
-            .try_get_compiler()?;
+        let cc = c_build(triple, opt).try_get_compiler()?;
         let out_path_flag = if cc.is_like_msvc() {
-            format!("/Fo\"{}\"", options.output.display())
+            format!("/Fo\"{}\"", output.display())
         } else {
-            format!("-o{}", options.output.display())
+            format!("-o{}", output.display())
         };
-        let output = cc
-            .to_command()
+        let mut command = cc.to_command();
+        if emit == Emit::Cdylib {
+            command.arg(if cc.is_like_msvc() { "/LD" } else { "-shared" });
+        }
+        command
             .arg(&tmp_out)
             .args(includes.iter())
-            .arg(out_path_flag)
-            .output()?;
-        if !output.status.success() {
-            std::io::stderr().lock().write_all(&output.stderr)?;
-            std::io::stderr().lock().write_all(&output.stderr)?;
+            .args(options.libraries.iter().flat_map(Library::link_args))
+            .args(stdlib.iter())
+            .arg(out_path_flag);
+        let result = command.output()?;
+        if !result.status.success() {
+            return Err(BuildError::Link {
+                command: format!("{:?}", command),
+                output: captured(&result),
+            }
+            .into());
         }
 
-        std::fs::remove_file(tmp_out)?;
-        for include in includes {
-            std::fs::remove_file(include)?;
+        Ok(())
+    }
+
+    /// C header declaring the exported functions, to be written to `path`.
+    fn header(&self, path: &Path) -> String {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let guard: String = stem
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .chain("_H".chars())
+            .collect();
+        let mut header = format!(
+            "#ifndef {0}\n#define {0}\n\n#include <stdint.h>\n\n#ifdef __cplusplus\nextern \"C\" {{\n#endif\n\n",
+            guard
+        );
+        for (name, params) in &self.exports {
+            let params: Vec<_> = params.iter().map(|p| format!("uintptr_t {}", p)).collect();
+            header.push_str(&format!("uintptr_t {}({});\n", name, params.join(", ")));
         }
+        header.push_str("\n#ifdef __cplusplus\n}\n#endif\n\n#endif\n");
+        header
+    }
 
-        Ok(())
+    /// Whether the program calls a function of stdlib.c, declared or from the prelude.
+    fn uses_std(&self) -> bool {
+        prelude::FUNCTIONS.iter().any(|function| {
+            self.module
+                .get_function(function.name)
+                .map_or(false, |fn_val| fn_val.count_basic_blocks() == 0)
+        })
+    }
+
+    /// Compile stdlib.c into an object next to `output`.
+    fn compile_std(
+        &self,
+        target: &TargetTriple,
+        opt: u32,
+        output: &Path,
+        temps: &mut Temps,
+    ) -> Result<String> {
+        let source = temps.add(format!("{}.std.c", output.display()));
+        std::fs::write(&source, runtime::STDLIB)?;
+        Ok(temps.add(self.compile_c(&source, target, opt)?))
+    }
+
+    /// Compile stdlib.c into a static library next to `output`.
+    ///
+    /// Being an archive, it only provides the helpers no other object defines, so programs
+    /// still linked with `-I stdlib.c` keep working.
+    fn archive_std(
+        &self,
+        target: &TargetTriple,
+        opt: u32,
+        output: &Path,
+        temps: &mut Temps,
+    ) -> Result<String> {
+        let object = self.compile_std(target, opt, output, temps)?;
+        let msvc = c_build(target, opt).try_get_compiler()?.is_like_msvc();
+        let archive_path = temps.add(if msvc {
+            format!("{}.std.lib", output.display())
+        } else {
+            format!("{}.std.a", output.display())
+        });
+        archive(target, opt, &archive_path, &[object])?;
+        Ok(archive_path)
+    }
+
+    fn run_jit(&self, options: RunOptions) -> Result<i32> {
+        Target::initialize_native(&InitializationConfig::default()).map_err(Error::msg)?;
+        for library in &options.libraries {
+            let path = CString::new(library.path.display().to_string())?;
+            let failed = unsafe { llvm_sys::support::LLVMLoadLibraryPermanently(path.as_ptr()) };
+            // System libraries can be linker scripts, those are part of the process already
+            if failed != 0 && !library.system {
+                bail!("failed to load {}", library.path.display());
+            }
+        }
+        let engine = self
+            .module
+            .create_jit_execution_engine(options.optimization)
+            .map_err(|e| anyhow!("failed to create execution engine: {}", e))?;
+
+        // Anything not mapped here is looked up in the process by the JIT
+        for (name, addr) in runtime::symbols() {
+            if let Some(fn_val) = self.module.get_function(name) {
+                // A beatsaber function can have the same name as a runtime helper
+                if fn_val.count_basic_blocks() == 0 {
+                    engine.add_global_mapping(&fn_val, addr);
+                }
+            }
+        }
+
+        let args = options
+            .args
+            .iter()
+            .map(|arg| CString::new(arg.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut argv: Vec<*const c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+        argv.push(ptr::null());
+
+        let code = unsafe {
+            let main = engine
+                .get_function::<MainFn>("main")
+                .map_err(|e| anyhow!("failed to look up main: {}", e))?;
+            main.call(args.len() as u64, argv.as_ptr() as u64)
+        };
+        Ok(code as i32)
     }
 
     fn compile_c(&self, file: impl AsRef<Path>, target: &TargetTriple, opt: u32) -> Result<String> {
         let file = file.as_ref();
         let out_file = format!("{}.tmp", file.display());
-        let cc = cc::Build::new()
-            .target(target.as_str().to_str().unwrap())
-            .host(env!("HOST"))
-            .opt_level(opt)
-            .cargo_metadata(false)
-            .try_get_compiler()?;
+        let cc = c_build(target, opt).try_get_compiler()?;
         let no_link_flag = if cc.is_like_msvc() { "/c" } else { "-c" };
         let out_path_flag = if cc.is_like_msvc() {
             format!("/Fo\"{}\"", out_file)
         } else {
             format!("-o{}", out_file)
         };
-        let output = cc
-            .to_command()
-            .arg(file)
-            .arg(no_link_flag)
-            .arg(out_path_flag)
-            .output()?;
-        if !output.status.success() {
-            std::io::stderr().lock().write_all(&output.stderr)?;
-            std::io::stderr().lock().write_all(&output.stderr)?;
+        let mut command = cc.to_command();
+        command.arg(file).arg(no_link_flag).arg(out_path_flag);
+        let result = command.output()?;
+        if !result.status.success() {
+            return Err(BuildError::CompileC {
+                file: file.to_owned(),
+                command: format!("{:?}", command),
+                output: captured(&result),
+            }
+            .into());
         }
         Ok(out_file)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Artifact, BuildError, Codegen, CodegenOptions, Emit, OptLevel, RunOptions};
+    use crate::CompileError;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    /// A directory of its own for the test `name`, per process so concurrent runs don't share it.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("beatsaber_codegen_test_{}", std::process::id()))
+            .join(name);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Compile `src` into the directory of the test `name`, to the file `name` there, with
+    /// `configure` applied to the default options.
+    fn build(
+        name: &str,
+        src: &str,
+        configure: impl FnOnce(&mut CodegenOptions),
+    ) -> Result<Artifact, CompileError> {
+        let output = test_dir(name).join(name);
+        let mut options = CodegenOptions::new(&output);
+        configure(&mut options);
+        crate::compile_str(src, "test.beatsaber", 0, options)
+    }
+
+    /// Compile `src` to LLVM IR, see `build`.
+    fn ir(name: &str, src: &str, configure: impl FnOnce(&mut CodegenOptions)) -> String {
+        let artifact = build(name, src, |options| {
+            options.emit = vec![Emit::LlvmIr];
+            configure(options);
+        })
+        .unwrap();
+        fs::read_to_string(&artifact.outputs[0].1).unwrap()
+    }
+
+    /// JIT compile and run `src`, returning the exit code.
+    fn jit(src: &str, intrinsics: bool) -> i32 {
+        let ast = crate::parse_str(src, "test.beatsaber", 0).unwrap();
+        let options = RunOptions {
+            optimization: OptLevel::None,
+            args: &["test".to_owned()],
+            goto_checks: true,
+            libraries: Vec::new(),
+            intrinsics,
+        };
+        Codegen::run(ast, options).unwrap()
+    }
+
+    /// The build error of a compilation that is expected to fail in the C compiler.
+    fn build_error(result: Result<Artifact, CompileError>) -> BuildError {
+        match result {
+            Err(CompileError::Build(e)) => e.downcast().unwrap(),
+            Err(e) => panic!("expected a build error, got {}", e),
+            Ok(_) => panic!("expected a build error"),
+        }
+    }
+
+    #[test]
+    fn intrinsics_match_stdlib() {
+        // Values are unsigned, so `w` is larger than `a` and `bnot` of zero is all ones
+        let cases = [
+            ("a.b // r is add", 10),
+            ("a.b // r is sub", 4),
+            ("a. // r is inc", 8),
+            ("a. // r is dec", 6),
+            ("w.a // r is less", 0),
+            ("w.a // r is greater", 1),
+            ("b.a // r is less", 1),
+            ("a.b // r is equal", 0),
+            ("a.a // r is equal", 1),
+            ("a.b // r is or", 7),
+            ("a.b // r is xor", 4),
+            ("zero. // r is not", 1),
+            ("a. // r is not", 0),
+            ("zero. // r is bnot", -1),
+            ("m.a // yeet is store\nm. // r is deref", 7),
+        ];
+        for (stmt, expected) in cases.iter() {
+            let src = format!(
+                "// calloc is not here this is big
+// a is 7
+// b is 3
+// zero is 0
+b.a // w is sub
+a.a // m is calloc
+{}
+r // return is
+",
+                stmt
+            );
+            assert_eq!(jit(&src, true), *expected, "intrinsic {}", stmt);
+            assert_eq!(jit(&src, false), *expected, "stdlib.c {}", stmt);
+        }
+    }
+
+    #[test]
+    fn no_intrinsics_calls_stdlib() {
+        let src = "// a is 7\na.a // r is add\nr // return is\n";
+        let inlined = ir("intrinsics", src, |_| {});
+        assert!(!inlined.contains("@add"));
+        let called = ir("no_intrinsics", src, |options| options.intrinsics = false);
+        assert!(called.contains("call i64 @add(i64"));
+    }
+
+    #[test]
+    fn deterministic() {
+        let src = include_str!("../examples/bf.beatsaber");
+        let outputs: Vec<Vec<Vec<u8>>> = ["deterministic_first", "deterministic_second"]
+            .iter()
+            .map(|name| {
+                let artifact = build(name, src, |options| {
+                    options.emit = vec![Emit::LlvmIr, Emit::Obj];
+                    options.debug_info = true;
+                })
+                .unwrap();
+                artifact
+                    .outputs
+                    .iter()
+                    .map(|(_, path)| fs::read(path).unwrap())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn debug_info() {
+        let src = "// twice is with n
+n.n // still in twice r is add
+r // still in twice return is
+// a is 21
+a. // b is twice
+b // return is
+";
+        let ir = ir("debug_info", src, |options| {
+            options.debug_info = true;
+            options.optimization = OptLevel::None;
+        });
+        assert_eq!(ir.matches("!DISubprogram(").count(), 2);
+        assert!(ir.contains("!DISubprogram(name: \"twice\","));
+        assert!(ir.contains("!DISubprogram(name: \"main\","));
+        // Instructions point at the line of the statement they were built from
+        assert!(ir.contains("!DILocation(line: 2,"));
+        assert!(ir.contains("!DILocation(line: 5,"));
+        assert!(ir.contains("!DILocalVariable(name: \"n\", arg: 1,"));
+        assert!(ir.contains("!DILocalVariable(name: \"r\", scope:"));
+        assert!(ir.contains("!DILocalVariable(name: \"b\", scope:"));
+    }
+
+    #[test]
+    fn labels_take_no_block_addresses() {
+        let ir = ir(
+            "labels",
+            include_str!("../examples/multiply.beatsaber"),
+            |_| {},
+        );
+        assert!(!ir.contains("blockaddress"));
+        assert!(!ir.contains(".lines"));
+    }
+
+    #[test]
+    fn line_table_per_function() {
+        let src = "// f is with n
+n // still in f goto is
+// still in f six is 6
+six // still in f return is
+// g is with n
+// still in g zero is 0
+// still in g here is top
+top // still in g if zero goto is
+n // still in g return is
+// five is 5
+five. // x is f
+x. // return is g
+";
+        let ir = ir("line_table", src, |_| {});
+        assert!(ir.contains("@f.lines = "));
+        assert_eq!(ir.matches(".lines = ").count(), 1);
+    }
+
+    /// Jumps to line 99 when run without arguments, which is computed so it isn't checked by
+    /// goto::resolve.
+    const INVALID_GOTO: &str = "// n is 98
+argc.n // t is add
+t // goto is
+// a is 0
+a // return is
+";
+
+    #[test]
+    fn invalid_goto() {
+        let artifact = build("invalid_goto", INVALID_GOTO, |_| {}).unwrap();
+        let run = Command::new(&artifact.outputs[0].1).output().unwrap();
+        assert!(!run.status.success());
+        let stderr = String::from_utf8_lossy(&run.stderr);
+        assert!(
+            stderr.contains("goto to invalid line 99 at line 3"),
+            "{}",
+            stderr
+        );
+    }
+
+    #[test]
+    fn no_goto_checks() {
+        let checked = ir("goto_checks", INVALID_GOTO, |_| {});
+        assert!(checked.contains("@bs_goto_failed"));
+        let unchecked = ir("no_goto_checks", INVALID_GOTO, |options| {
+            options.goto_checks = false
+        });
+        assert!(!unchecked.contains("bs_goto_failed"));
+    }
+
+    #[test]
+    fn compile_c_error() {
+        let include = test_dir("compile_c_error").join("broken.c");
+        fs::write(&include, "int broken( {\n").unwrap();
+        let result = build(
+            "compile_c_error",
+            "// a is 0\na // return is\n",
+            |options| {
+                options.include_c = vec![include.display().to_string()];
+            },
+        );
+        match build_error(result) {
+            BuildError::CompileC { file, output, .. } => {
+                assert_eq!(file, include);
+                assert!(output.contains("broken.c"), "{}", output);
+            }
+            e => panic!("expected a C compile error, got {}", e),
+        }
+        // The object of the program is removed without --keep-temps
+        let tmp = test_dir("compile_c_error").join("compile_c_error.tmp");
+        assert!(!tmp.exists());
+    }
+
+    #[test]
+    fn link_error() {
+        let src = "// bs_missing_function is not here\n// a is 0\na. // r is bs_missing_function\nr // return is\n";
+        let result = build("link_error", src, |options| options.keep_temps = true);
+        match build_error(result) {
+            BuildError::Link { output, .. } => {
+                assert!(output.contains("bs_missing_function"), "{}", output);
+            }
+            e => panic!("expected a link error, got {}", e),
+        }
+        assert!(test_dir("link_error").join("link_error.tmp").exists());
+    }
+
+    /// A library exporting `twice`, without a main.
+    const TWICE: &str =
+        "// twice is with n\nn.n // still in twice r is add\nr // still in twice return is\n";
+
+    /// Link a C program calling `twice` against `library` and run it.
+    #[cfg(unix)]
+    fn call_from_c(name: &str, library: &std::path::Path) {
+        let dir = test_dir(name);
+        let caller = dir.join("caller.c");
+        fs::write(
+            &caller,
+            "#include <stdint.h>\nuintptr_t twice(uintptr_t n);\nint main(void) { return twice(21) == 42 ? 0 : 1; }\n",
+        )
+        .unwrap();
+        let exe = dir.join("caller");
+        let cc = super::c_build(&super::TargetMachine::get_default_triple(), 0).get_compiler();
+        let status = cc
+            .to_command()
+            .arg(&caller)
+            .arg(library)
+            .arg("-o")
+            .arg(&exe)
+            .status()
+            .unwrap();
+        assert!(
+            status.success(),
+            "failed to link against {}",
+            library.display()
+        );
+        assert_eq!(Command::new(&exe).status().unwrap().code(), Some(0));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn staticlib() {
+        let artifact = build("staticlib", TWICE, |options| {
+            options.emit = vec![Emit::StaticLib];
+            options.main = false;
+        })
+        .unwrap();
+        call_from_c("staticlib", &artifact.outputs[0].1);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn cdylib() {
+        let artifact = build("cdylib", TWICE, |options| {
+            options.emit = vec![Emit::Cdylib];
+            options.main = false;
+        })
+        .unwrap();
+        // Linked by its absolute path, so the loader finds it without a search path
+        call_from_c("cdylib", &artifact.outputs[0].1);
+    }
+
+    #[test]
+    fn cdylib_needs_no_main() {
+        let result = build("cdylib_needs_no_main", TWICE, |options| {
+            options.emit = vec![Emit::Cdylib];
+        });
+        match result {
+            Err(CompileError::Build(e)) => {
+                assert_eq!(e.to_string(), "--emit=cdylib needs --no-main")
+            }
+            Err(e) => panic!("expected a build error, got {}", e),
+            Ok(_) => panic!("expected a build error"),
+        }
+    }
+
+    #[test]
+    fn target_machine_errors() {
+        let machine = |cpu: &str, feature: &str| {
+            super::create_target_machine(
+                Some("x86_64-unknown-linux-gnu"),
+                cpu,
+                &[feature.to_owned()],
+                OptLevel::None,
+                super::RelocMode::Default,
+            )
+        };
+        let error = machine("haswell", "avx2").err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "target feature avx2 must start with + or -"
+        );
+        if cfg!(unix) {
+            let error = machine("nocpu", "+avx2").err().unwrap();
+            assert_eq!(
+                error.to_string(),
+                "'nocpu' is not a recognized processor for this target, see --print target-cpus"
+            );
+            let error = machine("haswell", "+nofeature").err().unwrap().to_string();
+            assert!(
+                error.contains("nofeature' is not a recognized feature for this target"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn target_attributes() {
+        let ir = ir(
+            "target_attributes",
+            "// a is 5\na // return is\n",
+            |options| {
+                options.target = Some("x86_64-unknown-linux-gnu".to_string());
+                options.cpu = "haswell".to_string();
+                options.target_features = vec!["-avx2".to_string()];
+            },
+        );
+        assert!(ir.contains("\"target-cpu\"=\"haswell\""));
+        assert!(ir.contains("\"target-features\"=\"-avx2\""));
+    }
+
+    #[test]
+    fn wasm_debug_info() {
+        let src = "// a is 5\na // return is\n";
+        let ir = ir("wasm_debug_info", src, |options| {
+            options.target = Some("wasm32-wasi".to_string());
+            options.debug_info = true;
+        });
+        assert!(ir.contains("!DIBasicType(name: \"u32\", size: 32, encoding: DW_ATE_unsigned)"));
+    }
+
+    #[test]
+    #[ignore = "needs wasmtime and a wasm32-wasi C compiler, like the clang of wasi-sdk in CC_wasm32_wasi"]
+    fn wasm() {
+        // f jumps to the line its parameter names, which takes the switch on wasm
+        let src = "// f is with n\nn // still in f goto is\n// still in f six is 6\nsix // still in f return is\n// still in f seven is 7\nseven // still in f return is\n// hi is \"hi\"\nhi. // yeet is putsnl\n// five is 5\nfive. // r is f\nr // return is\n";
+        let artifact = build("wasm", src, |options| {
+            options.target = Some("wasm32-wasi".to_string());
+        })
+        .unwrap();
+        let run = Command::new("wasmtime")
+            .arg(&artifact.outputs[0].1)
+            .output()
+            .unwrap();
+        assert_eq!(run.stdout, b"hi\n");
+        assert_eq!(run.status.code(), Some(7));
+    }
+
+    #[test]
+    fn header() {
+        let src = "// fib is with n here for all\nn // still in fib return is\n// f is with x\nx // still in f return is\n// add2 is with a and b here for all\na // still in add2 return is\n";
+        // The include guard is made from the file name
+        let artifact = build("fib-lib", src, |options| {
+            options.emit = vec![Emit::Header];
+            options.optimization = OptLevel::None;
+        })
+        .unwrap();
+        let header = fs::read_to_string(&artifact.outputs[0].1).unwrap();
+        assert!(header.starts_with("#ifndef FIB_LIB_H\n#define FIB_LIB_H\n"));
+        assert!(header.contains(
+            "\nuintptr_t fib(uintptr_t n);\nuintptr_t add2(uintptr_t a, uintptr_t b);\n\n"
+        ));
+    }
+}
@@ -22,9 +22,4 @@ impl<'a> Reporter<'a> {
         let config = term::Config::default();
         term::emit(&mut writer, &config, &self.source, diagnostic).ok();
     }
-
-    pub fn report_and_exit(&self, diagnostic: &Diagnostic) -> ! {
-        self.report(diagnostic);
-        std::process::exit(1)
-    }
 }
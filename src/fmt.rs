@@ -0,0 +1,225 @@
+//! Canonical layout for beatsaber source, used by `bsc fmt`.
+//!
+//! Only spacing within a line is changed. `goto` jumps to line numbers, so lines are never
+//! added, removed or joined, except for blank lines at the end of the file.
+
+use crate::ast1::{self, AssignTarget, AssignValue, Behaviour, Expr, Op, Stmt};
+use crate::error::Diagnostic;
+use crate::lexer;
+use std::collections::BTreeMap;
+
+/// Format a program, or return the syntax errors that prevent formatting it.
+pub fn format(src: &str) -> Result<String, Vec<Diagnostic>> {
+    let mut parser = ast1::parser(lexer::lexer(src, ""));
+    let mut lines: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    let mut errors = Vec::new();
+    for stmt in parser.by_ref() {
+        match stmt {
+            Ok(stmt) => {
+                let mut out = String::new();
+                write_stmt(src, &stmt, &mut out);
+                lines.entry(stmt.line).or_default().push(out);
+            }
+            Err(diagnostic) => errors.push(diagnostic),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    for (line, span) in parser.comments() {
+        lines
+            .entry(*line)
+            .or_default()
+            .push(src[span.clone()].to_owned());
+    }
+
+    let mut out = String::new();
+    let mut next_line = 1;
+    for (line, parts) in lines {
+        for _ in next_line..line {
+            out.push('\n');
+        }
+        out.push_str(&parts.join(" "));
+        out.push('\n');
+        next_line = line + 1;
+    }
+    Ok(out)
+}
+
+fn write_stmt(src: &str, stmt: &Stmt, out: &mut String) {
+    if let Some(expr) = &stmt.expr {
+        write_expr(src, expr, out);
+        out.push(' ');
+    }
+    out.push_str("// ");
+    write_behaviour(src, &stmt.behaviour, out);
+}
+
+fn write_expr(src: &str, expr: &Expr, out: &mut String) {
+    match expr {
+        Expr::Binop { lhs, rhs, .. } => {
+            write_expr(src, lhs, out);
+            out.push('.');
+            write_expr(src, rhs, out);
+        }
+        Expr::Unop { expr, .. } => {
+            write_expr(src, expr, out);
+            out.push('.');
+        }
+        Expr::Paren { expr, .. } => {
+            out.push('(');
+            write_expr(src, expr, out);
+            out.push(')');
+        }
+        Expr::Args { args, .. } => {
+            out.push('(');
+            for (i, arg) in args.iter().enumerate() {
+                if i != 0 {
+                    out.push_str(" and ");
+                }
+                write_expr(src, arg, out);
+            }
+            out.push(')');
+        }
+        Expr::Ident(span) => out.push_str(&src[span.clone()]),
+    }
+}
+
+fn write_behaviour(src: &str, behaviour: &Behaviour, out: &mut String) {
+    match behaviour {
+        Behaviour::StillIn {
+            ident, behaviour, ..
+        } => {
+            out.push_str("still in ");
+            out.push_str(&src[ident.clone()]);
+            out.push(' ');
+            write_behaviour(src, behaviour, out);
+        }
+        Behaviour::Cond {
+            cond, behaviour, ..
+        } => {
+            out.push_str("if ");
+            out.push_str(&src[cond.clone()]);
+            out.push(' ');
+            write_behaviour(src, behaviour, out);
+        }
+        Behaviour::Label { name, .. } => {
+            out.push_str("here is ");
+            out.push_str(&src[name.clone()]);
+        }
+        Behaviour::Assign { target, value, .. } => {
+            // Keep the spelling of the target, discard can be `yeet` or `fuckall`
+            let (AssignTarget::Discard(span)
+            | AssignTarget::Return(span)
+            | AssignTarget::Goto(span)
+            | AssignTarget::Ident(span)) = target;
+            out.push_str(&src[span.clone()]);
+            out.push_str(" is");
+            write_value(src, value, out);
+        }
+    }
+}
+
+/// Write an assignment value, including the space that separates it from `is`.
+fn write_value(src: &str, value: &AssignValue, out: &mut String) {
+    match value {
+        AssignValue::Ops(ops) => write_ops(src, ops, out),
+        AssignValue::Fn(f) => {
+            out.push_str(" with ");
+            for (i, name) in f.params.names.iter().enumerate() {
+                if i != 0 {
+                    out.push_str(" and ");
+                }
+                out.push_str(&src[name.clone()]);
+            }
+            if f.export.is_some() {
+                out.push_str(" here for all");
+            }
+            write_ops(src, &f.ops, out);
+        }
+        AssignValue::Number(span, _) | AssignValue::String(span) => {
+            out.push(' ');
+            out.push_str(&src[span.clone()]);
+        }
+        AssignValue::NotHere(not_here) => {
+            out.push_str(" not here");
+            if let Some(module) = &not_here.ident {
+                out.push_str(" but is in ");
+                out.push_str(&src[module.clone()]);
+            }
+            if not_here.and_is_big.is_some() {
+                out.push_str(" this is big");
+            }
+            if let Some((span, _)) = &not_here.params {
+                out.push(' ');
+                out.push_str(&src[span.clone()]);
+            }
+            if not_here.or_more.is_some() {
+                out.push_str(" or more");
+            }
+        }
+    }
+}
+
+fn write_ops(src: &str, ops: &[Op], out: &mut String) {
+    for (i, op) in ops.iter().enumerate() {
+        out.push_str(if i == 0 { " " } else { " then " });
+        out.push_str(&src[op.ident.clone()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+    use crate::lexer::Token;
+    use logos::Logos;
+    use std::fs;
+
+    fn tokens(src: &str) -> Vec<(Token, &str)> {
+        let mut lex = Token::lexer(src);
+        let mut tokens = Vec::new();
+        while let Some(token) = lex.next() {
+            tokens.push((token, lex.slice()));
+        }
+        // Trailing blank lines are dropped
+        while let Some((Token::Newline, _)) = tokens.last() {
+            tokens.pop();
+        }
+        tokens
+    }
+
+    #[test]
+    fn layout() {
+        let src = "\n//  one   is 1  * the number one\n  (one.one ) . //if one yeet   is\tinc\n\n* done\n\n\n";
+        let formatted = format(src).unwrap();
+        assert_eq!(
+            formatted,
+            "\n// one is 1 * the number one\n(one.one). // if one yeet is inc\n\n* done\n"
+        );
+    }
+
+    #[test]
+    fn examples() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension() != Some("beatsaber".as_ref()) {
+                continue;
+            }
+            let src = fs::read_to_string(&path).unwrap();
+            let formatted = format(&src).unwrap();
+            assert_eq!(
+                format(&formatted).unwrap(),
+                formatted,
+                "{} is not formatted idempotently",
+                path.display()
+            );
+            assert_eq!(
+                tokens(&formatted),
+                tokens(&src),
+                "formatting changed the meaning of {}",
+                path.display()
+            );
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! Resolution of `goto` statements that always jump to the same line.
+//!
+//! A goto whose target is an identifier bound once to a number literal, and never assigned
+//! again, jumps to a line known before the program runs. Those lines are checked here and
+//! stored in `GotoStmt::dest`, so codegen can branch to them directly instead of going through
+//! the line table. Labels are bound to the line they are on, so a goto to a label is always
+//! resolved.
+//!
+//! [`lint`] suggests labels for number literals that are only used as goto targets.
+
+use crate::ast2::{self, Callable, DecoratedExpr, DecoratedStmt, FuncBlock, GotoStmt};
+use crate::error::{Diagnostic, Label};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Default)]
+struct Program {
+    /// Identifier id -> number literal it is bound to
+    literals: HashMap<usize, usize>,
+    /// Identifiers that are assigned anything other than a single unconditional literal
+    assigned: HashSet<usize>,
+    /// Line -> function owning the statement on it, None for main
+    lines: BTreeMap<usize, Option<usize>>,
+    /// Function id -> name
+    functions: HashMap<usize, String>,
+    /// Identifier id -> gotos jumping to it, for identifiers that are only used as whole targets
+    targets: HashMap<usize, Vec<GotoStmt>>,
+    /// Identifiers read anywhere else, or labels
+    used: HashSet<usize>,
+    /// Identifier id -> number literal statement binding it
+    bindings: HashMap<usize, ast2::Identifier>,
+}
+
+impl Program {
+    fn new(ast: &[DecoratedStmt]) -> Self {
+        let mut program = Program::default();
+        // Set by the caller of main
+        program.assigned.insert(ast2::ARGC_ID);
+        program.assigned.insert(ast2::ARGV_ID);
+        for stmt in ast {
+            program.add_stmt(stmt, None, false);
+        }
+        program
+    }
+
+    fn add_stmt(&mut self, stmt: &DecoratedStmt, func: Option<usize>, conditional: bool) {
+        if let DecoratedStmt::Callable(callable) = stmt {
+            if let Callable::FuncBlock(FuncBlock { decl, block }) = callable {
+                self.functions.insert(decl.id.id, decl.id.name.clone());
+                self.assigned
+                    .extend(decl.params.iter().map(|param| param.id));
+                for stmt in block {
+                    self.add_stmt(stmt, Some(decl.id.id), false);
+                }
+            }
+            return;
+        }
+        self.lines.insert(stmt.line_number(), func);
+        match stmt {
+            DecoratedStmt::LoadLiteralNumber(stmt) => {
+                let id = stmt.ident.id;
+                if conditional || self.literals.insert(id, stmt.value).is_some() {
+                    self.assigned.insert(id);
+                }
+                self.bindings.insert(id, stmt.ident.clone());
+            }
+            DecoratedStmt::LoadLiteralString(stmt) => {
+                self.assigned.insert(stmt.ident.id);
+            }
+            DecoratedStmt::Label(stmt) => {
+                self.literals.insert(stmt.ident.id, stmt.line);
+                self.used.insert(stmt.ident.id);
+            }
+            DecoratedStmt::Assignment(stmt) => {
+                if let Some(name) = &stmt.name {
+                    self.assigned.insert(name.id);
+                }
+                self.add_use(&stmt.value);
+            }
+            DecoratedStmt::ReturnStmt(stmt) => self.add_use(&stmt.expr),
+            DecoratedStmt::GotoStmt(stmt) => match &stmt.target {
+                DecoratedExpr::Identifier(ident) => {
+                    self.targets.entry(ident.id).or_default().push(stmt.clone())
+                }
+                target => self.add_use(target),
+            },
+            DecoratedStmt::Conditional(stmt) => {
+                self.used.insert(stmt.condition.id);
+                self.add_stmt(&stmt.success, func, true)
+            }
+            DecoratedStmt::Callable(_) => {}
+        }
+    }
+
+    fn add_use(&mut self, expr: &DecoratedExpr) {
+        match expr {
+            DecoratedExpr::Identifier(ident) => {
+                self.used.insert(ident.id);
+            }
+            DecoratedExpr::CallExpr(expr) => {
+                for arg in &expr.args {
+                    self.add_use(arg);
+                }
+            }
+        }
+    }
+
+    fn constant(&self, expr: &DecoratedExpr) -> Option<usize> {
+        match expr {
+            DecoratedExpr::Identifier(ident) if !self.assigned.contains(&ident.id) => {
+                self.literals.get(&ident.id).copied()
+            }
+            _ => None,
+        }
+    }
+
+    fn describe(&self, func: Option<usize>) -> String {
+        match func {
+            Some(func) => format!("function `{}`", self.functions[&func]),
+            None => "the main program".to_owned(),
+        }
+    }
+
+    fn resolve_stmt(
+        &self,
+        stmt: &mut DecoratedStmt,
+        func: Option<usize>,
+        errors: &mut Vec<Diagnostic>,
+    ) {
+        match stmt {
+            DecoratedStmt::Callable(Callable::FuncBlock(block)) => {
+                let func = Some(block.decl.id.id);
+                for stmt in &mut block.block {
+                    self.resolve_stmt(stmt, func, errors);
+                }
+            }
+            DecoratedStmt::Conditional(stmt) => self.resolve_stmt(&mut stmt.success, func, errors),
+            DecoratedStmt::GotoStmt(stmt) => match self.resolve_goto(stmt, func) {
+                Ok(dest) => stmt.dest = dest,
+                Err(diagnostic) => errors.push(diagnostic),
+            },
+            _ => {}
+        }
+    }
+
+    fn resolve_goto(
+        &self,
+        stmt: &GotoStmt,
+        func: Option<usize>,
+    ) -> Result<Option<usize>, Diagnostic> {
+        let dest = match self.constant(&stmt.target) {
+            Some(dest) => dest,
+            None => return Ok(None),
+        };
+        let bound_here = match &stmt.target {
+            DecoratedExpr::Identifier(ident) => Label::secondary((), ident.decl.clone())
+                .with_message(format!("`{}` is bound to {} here", ident.name, dest)),
+            DecoratedExpr::CallExpr(_) => unreachable!("only identifiers are constant"),
+        };
+        let last = self.lines.keys().next_back().copied().unwrap_or(0);
+        match self.lines.get(&dest) {
+            Some(&owner) if owner == func => Ok(Some(dest)),
+            Some(&owner) => Err(Diagnostic::error()
+                .with_message("goto into another function")
+                .with_labels(vec![
+                    Label::primary((), stmt.span.clone()).with_message(format!(
+                        "this jumps to line {}, which is in {}",
+                        dest,
+                        self.describe(owner)
+                    )),
+                    bound_here,
+                ])
+                .with_notes(vec![format!(
+                    "a goto can only jump within {}",
+                    self.describe(func)
+                )])),
+            None if dest == 0 || dest > last => Err(Diagnostic::error()
+                .with_message("goto target out of range")
+                .with_labels(vec![
+                    Label::primary((), stmt.span.clone()).with_message(format!(
+                        "this jumps to line {}, but the last statement is on line {}",
+                        dest, last
+                    )),
+                    bound_here,
+                ])),
+            None => Err(Diagnostic::error()
+                .with_message("goto target has no statement")
+                .with_labels(vec![
+                    Label::primary((), stmt.span.clone())
+                        .with_message(format!("there is no statement on line {}", dest)),
+                    bound_here,
+                ])),
+        }
+    }
+}
+
+/// Check every goto with a constant target and record the line it jumps to.
+pub fn resolve(ast: &mut [DecoratedStmt]) -> Result<(), Vec<Diagnostic>> {
+    let program = Program::new(ast);
+    let mut errors = Vec::new();
+    for stmt in ast {
+        program.resolve_stmt(stmt, None, &mut errors);
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Warn about number literals that are only used as goto targets, a label says the same thing
+/// and keeps working when lines are added or removed.
+pub fn lint(ast: &[DecoratedStmt]) -> Vec<Diagnostic> {
+    let program = Program::new(ast);
+    let mut warnings: Vec<_> = program
+        .targets
+        .iter()
+        .filter(|(id, _)| !program.used.contains(id))
+        .filter_map(|(id, gotos)| {
+            let dest = program.constant(&gotos[0].target)?;
+            let ident = &program.bindings[id];
+            let still_in = match program.lines.get(&dest)? {
+                Some(func) => format!("still in {} ", program.functions[func]),
+                None => String::new(),
+            };
+            let mut labels = vec![Label::primary((), ident.decl.clone())
+                .with_message(format!("`{}` is only used as the target of a goto", ident.name))];
+            labels.extend(gotos.iter().map(|goto| {
+                Label::secondary((), goto.span.clone())
+                    .with_message(format!("jumps to line {} here", dest))
+            }));
+            Some(
+                Diagnostic::warning()
+                    .with_message("line number used as a goto target")
+                    .with_labels(labels)
+                    .with_notes(vec![format!(
+                        "use a label instead: put `// {}here is {}` on a line of its own just before line {}",
+                        still_in, ident.name, dest
+                    )]),
+            )
+        })
+        .collect();
+    warnings.sort_by_key(|warning| warning.labels[0].range.start);
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast2::{Callable, DecoratedStmt};
+
+    fn errors(src: &str) -> Vec<String> {
+        crate::parse_str(src, "test.beatsaber", 0)
+            .unwrap_err()
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect()
+    }
+
+    #[test]
+    fn dests() {
+        let src = include_str!("../examples/bf.beatsaber");
+        let ast = crate::parse_str(src, "bf.beatsaber", 0).unwrap();
+        let mut dests = Vec::new();
+        for stmt in &ast {
+            match stmt {
+                DecoratedStmt::GotoStmt(stmt) => dests.push(stmt.dest),
+                DecoratedStmt::Callable(Callable::FuncBlock(block)) => {
+                    for stmt in &block.block {
+                        if let DecoratedStmt::GotoStmt(stmt) = stmt {
+                            dests.push(stmt.dest);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        dests.sort_unstable();
+        assert_eq!(dests, [Some(61), Some(76), Some(87)]);
+    }
+
+    #[test]
+    fn lint() {
+        let src = "// add is not here this is big\n// a is 5\n// b is 6\n// c is 6\nb // goto is\na // goto is\nc.c // d is add\nc // goto is\n";
+        let ast = crate::parse_str(src, "test.beatsaber", 0).unwrap();
+        let warnings = super::lint(&ast);
+        // `c` is also read by an operation
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(&src[warnings[0].labels[0].range.clone()], "a");
+        assert_eq!(&src[warnings[1].labels[0].range.clone()], "b");
+
+        let src = include_str!("../examples/bf.beatsaber");
+        let ast = crate::parse_str(src, "bf.beatsaber", 0).unwrap();
+        assert!(super::lint(&ast).is_empty());
+    }
+
+    #[test]
+    fn invalid() {
+        let src = "// a is 3\n// b is 99\n// f is with x\n// still in f c is 1\n// c is 4\n\na // goto is\nb // goto is\nc // goto is\n";
+        assert_eq!(
+            errors(src),
+            [
+                "goto target has no statement",
+                "goto target out of range",
+                "goto into another function",
+            ]
+        );
+    }
+}
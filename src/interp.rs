@@ -0,0 +1,565 @@
+//! Tree-walking interpreter over the output of `ast2::parse`, for running programs without LLVM.
+//!
+//! Values are plain 64bit integers and pointers are real process addresses, so the builtins
+//! behave exactly like their stdlib.c counterparts in a compiled program would.
+
+use crate::ast2::{self, Callable, DecoratedExpr, DecoratedStmt, FuncBlock};
+use anyhow::{anyhow, bail, Result};
+use std::alloc::{self, Layout};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{CStr, CString};
+use std::io::{self, Read, Write};
+use std::os::raw::{c_char, c_int, c_void};
+use std::{fs, mem, ptr};
+
+pub type BuiltinFn = dyn FnMut(&[u64]) -> u64;
+
+pub struct Builtin {
+    pub params: usize,
+    /// Takes `params` or more arguments, like functions declared `or more`
+    pub variadic: bool,
+    pub func: Box<BuiltinFn>,
+}
+
+pub struct Interpreter {
+    builtins: HashMap<String, Builtin>,
+}
+
+enum Flow {
+    Next,
+    Return(u64),
+    Goto(u64),
+}
+
+struct Function<'ast> {
+    params: Vec<usize>,
+    captures: Vec<usize>,
+    body: Vec<&'ast DecoratedStmt>,
+}
+
+struct Program<'ast> {
+    main: Vec<&'ast DecoratedStmt>,
+    functions: HashMap<usize, Function<'ast>>,
+    externs: HashMap<usize, &'ast str>,
+    /// line -> owning function, None for main
+    lines: BTreeMap<usize, Option<usize>>,
+    line_count: usize,
+}
+
+struct Frame {
+    func: Option<usize>,
+    locals: HashMap<usize, u64>,
+}
+
+impl<'ast> Program<'ast> {
+    fn new(ast: &'ast [DecoratedStmt]) -> Self {
+        let mut program = Program {
+            main: Vec::new(),
+            functions: HashMap::new(),
+            externs: HashMap::new(),
+            lines: BTreeMap::new(),
+            line_count: 0,
+        };
+        program.main = program.add_body(ast, None);
+        program
+    }
+
+    fn add_body(
+        &mut self,
+        stmts: &'ast [DecoratedStmt],
+        func: Option<usize>,
+    ) -> Vec<&'ast DecoratedStmt> {
+        let mut body = Vec::new();
+        for stmt in stmts {
+            let line = stmt.line_number();
+            self.line_count = self.line_count.max(line);
+            match stmt {
+                DecoratedStmt::Callable(Callable::ExternFunction(stmt)) => {
+                    self.externs.insert(stmt.ident.id, &stmt.name);
+                }
+                DecoratedStmt::Callable(Callable::FuncBlock(FuncBlock { decl, block })) => {
+                    let params: Vec<_> = decl.params.iter().map(|param| param.id).collect();
+                    let captures = ast2::find_captures(block, &params)
+                        .into_iter()
+                        .map(|capture| capture.id)
+                        .collect();
+                    let body = self.add_body(block, Some(decl.id.id));
+                    self.functions.insert(
+                        decl.id.id,
+                        Function {
+                            params,
+                            captures,
+                            body,
+                        },
+                    );
+                }
+                _ => {
+                    self.lines.insert(line, func);
+                    body.push(stmt);
+                }
+            }
+        }
+        body
+    }
+}
+
+impl Frame {
+    fn get(&self, id: usize) -> Result<u64> {
+        self.locals
+            .get(&id)
+            .copied()
+            .ok_or_else(|| anyhow!("could not find local {}", id))
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    /// Create an interpreter with the stdlib.c helpers and a few libc functions available.
+    pub fn new() -> Self {
+        let mut interp = Self::empty();
+        register_std(&mut interp);
+        interp
+    }
+
+    /// Create an interpreter without any builtins.
+    pub fn empty() -> Self {
+        Interpreter {
+            builtins: HashMap::new(),
+        }
+    }
+
+    /// Make `func` callable from programs declaring `name` as `not here`.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        params: usize,
+        func: impl FnMut(&[u64]) -> u64 + 'static,
+    ) {
+        self.builtins.insert(
+            name.into(),
+            Builtin {
+                params,
+                variadic: false,
+                func: Box::new(func),
+            },
+        );
+    }
+
+    /// Like `register`, for functions taking `params` or more arguments.
+    pub fn register_variadic(
+        &mut self,
+        name: impl Into<String>,
+        params: usize,
+        func: impl FnMut(&[u64]) -> u64 + 'static,
+    ) {
+        self.builtins.insert(
+            name.into(),
+            Builtin {
+                params,
+                variadic: true,
+                func: Box::new(func),
+            },
+        );
+    }
+
+    /// Run a program, returning the result of its top level.
+    /// `args` are passed as `argc` and `argv` and should include the program name.
+    pub fn run(&mut self, ast: &[DecoratedStmt], args: &[String]) -> Result<u64> {
+        let args = args
+            .iter()
+            .map(|arg| CString::new(arg.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut argv: Vec<*const c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+        argv.push(ptr::null());
+
+        let program = Program::new(ast);
+        let mut frame = Frame {
+            func: None,
+            locals: HashMap::new(),
+        };
+        frame.locals.insert(ast2::ARGC_ID, args.len() as u64);
+        frame.locals.insert(ast2::ARGV_ID, argv.as_ptr() as u64);
+
+        let mut state = State {
+            builtins: &mut self.builtins,
+            program: &program,
+            strings: HashMap::new(),
+        };
+        state.exec_body(&program.main, &mut frame)
+    }
+}
+
+struct State<'a, 'ast> {
+    builtins: &'a mut HashMap<String, Builtin>,
+    program: &'a Program<'ast>,
+    /// line -> string literal, kept alive for the whole run
+    strings: HashMap<usize, CString>,
+}
+
+impl State<'_, '_> {
+    fn exec_body(&mut self, body: &[&DecoratedStmt], frame: &mut Frame) -> Result<u64> {
+        let mut pc = 0;
+        while let Some(stmt) = body.get(pc) {
+            match self.exec_stmt(stmt, frame)? {
+                Flow::Next => pc += 1,
+                Flow::Return(val) => return Ok(val),
+                Flow::Goto(line) => {
+                    pc = self.resolve_goto(body, frame, line, stmt.line_number())?
+                }
+            }
+        }
+        Ok(0)
+    }
+
+    /// Find the statement a goto lands on, the same way the compiled line table does.
+    fn resolve_goto(
+        &self,
+        body: &[&DecoratedStmt],
+        frame: &Frame,
+        target: u64,
+        line: usize,
+    ) -> Result<usize> {
+        let invalid = || anyhow!("goto to invalid line {} at line {}", target, line);
+        if target == 0 || target > self.program.line_count as u64 {
+            return Err(invalid());
+        }
+        let (&dest, &owner) = self
+            .program
+            .lines
+            .range(target as usize..)
+            .next()
+            .ok_or_else(invalid)?;
+        if owner != frame.func {
+            return Err(invalid());
+        }
+        body.iter()
+            .position(|stmt| stmt.line_number() == dest)
+            .ok_or_else(invalid)
+    }
+
+    fn exec_stmt(&mut self, stmt: &DecoratedStmt, frame: &mut Frame) -> Result<Flow> {
+        match stmt {
+            DecoratedStmt::LoadLiteralNumber(stmt) => {
+                frame.locals.insert(stmt.ident.id, stmt.value as u64);
+            }
+            DecoratedStmt::LoadLiteralString(stmt) => {
+                let value = match self.strings.get(&stmt.line) {
+                    Some(string) => string.as_ptr(),
+                    None => {
+                        let string = CString::new(stmt.value.as_str())?;
+                        let ptr = string.as_ptr();
+                        self.strings.insert(stmt.line, string);
+                        ptr
+                    }
+                };
+                frame.locals.insert(stmt.ident.id, value as u64);
+            }
+            DecoratedStmt::Conditional(stmt) => {
+                if frame.get(stmt.condition.id)? != 0 {
+                    return self.exec_stmt(&stmt.success, frame);
+                }
+            }
+            DecoratedStmt::Assignment(stmt) => {
+                let val = self.eval(&stmt.value, frame)?;
+                if let Some(id) = &stmt.name {
+                    frame.locals.insert(id.id, val);
+                }
+            }
+            DecoratedStmt::ReturnStmt(stmt) => {
+                return Ok(Flow::Return(self.eval(&stmt.expr, frame)?))
+            }
+            DecoratedStmt::GotoStmt(stmt) => {
+                let line = match stmt.dest {
+                    Some(dest) => dest as u64,
+                    None => self.eval(&stmt.target, frame)?,
+                };
+                return Ok(Flow::Goto(line));
+            }
+            DecoratedStmt::Label(_) => {}
+            DecoratedStmt::Callable(_) => unreachable!("callables are not part of bodies"),
+        }
+        Ok(Flow::Next)
+    }
+
+    fn eval(&mut self, expr: &DecoratedExpr, frame: &Frame) -> Result<u64> {
+        match expr {
+            DecoratedExpr::Identifier(ident) => frame.get(ident.id),
+            DecoratedExpr::CallExpr(expr) => {
+                let args = expr
+                    .args
+                    .iter()
+                    .map(|arg| self.eval(arg, frame))
+                    .collect::<Result<Vec<_>>>()?;
+                self.call(expr.function.id, &args, frame)
+            }
+        }
+    }
+
+    fn call(&mut self, id: usize, args: &[u64], caller: &Frame) -> Result<u64> {
+        if let Some(&name) = self.program.externs.get(&id) {
+            let builtin = self
+                .builtins
+                .get_mut(name)
+                .ok_or_else(|| anyhow!("unresolved external function `{}`", name))?;
+            let accepts = match builtin.variadic {
+                true => args.len() >= builtin.params,
+                false => args.len() == builtin.params,
+            };
+            if !accepts {
+                bail!(
+                    "external function `{}` takes {}{} parameters but was called with {}",
+                    name,
+                    if builtin.variadic { "at least " } else { "" },
+                    builtin.params,
+                    args.len()
+                );
+            }
+            return Ok((builtin.func)(args));
+        }
+
+        let program = self.program;
+        let func = &program.functions[&id];
+        let mut frame = Frame {
+            func: Some(id),
+            locals: HashMap::new(),
+        };
+        for (&param, &arg) in func.params.iter().zip(args) {
+            frame.locals.insert(param, arg);
+        }
+        // Captures are passed by value from the caller, like the extra parameters in codegen
+        for &capture in &func.captures {
+            frame.locals.insert(capture, caller.get(capture)?);
+        }
+        self.exec_body(&func.body, &mut frame)
+    }
+}
+
+fn alloc_zeroed(size: u64) -> u64 {
+    let layout = Layout::from_size_align(size.max(1) as usize, 16).unwrap();
+    unsafe { alloc::alloc_zeroed(layout) as u64 }
+}
+
+fn c_str<'a>(ptr: u64) -> &'a CStr {
+    unsafe { CStr::from_ptr(ptr as *const c_char) }
+}
+
+fn write_stdout(bytes: &[u8]) -> u64 {
+    let mut stdout = io::stdout();
+    stdout.write_all(bytes).ok();
+    stdout.flush().ok();
+    bytes.len() as u64
+}
+
+extern "C" {
+    fn printf(format: *const c_char, ...) -> c_int;
+    fn fflush(stream: *mut c_void) -> c_int;
+}
+
+/// Call the C printf, flushing so its output stays in order with the other builtins.
+fn c_printf(a: &[u64]) -> u64 {
+    let format = a[0] as *const c_char;
+    let written = unsafe {
+        match a[1..] {
+            [] => printf(format),
+            [b] => printf(format, b),
+            [b, c] => printf(format, b, c),
+            [b, c, d] => printf(format, b, c, d),
+            [b, c, d, e] => printf(format, b, c, d, e),
+            [b, c, d, e, f] => printf(format, b, c, d, e, f),
+            [b, c, d, e, f, g] => printf(format, b, c, d, e, f, g),
+            _ => panic!("printf takes at most 7 parameters in the interpreter"),
+        }
+    };
+    unsafe { fflush(ptr::null_mut()) };
+    written as u64
+}
+
+fn register_std(interp: &mut Interpreter) {
+    // stdlib.c
+    interp.register("inc", 1, |a| a[0].wrapping_add(1));
+    interp.register("dec", 1, |a| a[0].wrapping_sub(1));
+    interp.register("store", 2, |a| {
+        unsafe { ptr::write_unaligned(a[0] as *mut u64, a[1]) };
+        0
+    });
+    interp.register("storeb", 2, |a| {
+        unsafe { *(a[0] as *mut u8) = a[1] as u8 };
+        0
+    });
+    interp.register("add", 2, |a| a[0].wrapping_add(a[1]));
+    interp.register("sub", 2, |a| a[0].wrapping_sub(a[1]));
+    interp.register("greater", 2, |a| (a[0] > a[1]) as u64);
+    interp.register("less", 2, |a| (a[0] < a[1]) as u64);
+    interp.register("deref", 1, |a| unsafe {
+        ptr::read_unaligned(a[0] as *const u64)
+    });
+    interp.register("equal", 2, |a| (a[0] == a[1]) as u64);
+    interp.register("equalchar", 2, |a| (a[0] as u8 == a[1] as u8) as u64);
+    interp.register("bnot", 1, |a| !a[0]);
+    interp.register("not", 1, |a| (a[0] == 0) as u64);
+    interp.register("and", 2, |a| a[0] & a[1]);
+    interp.register("or", 2, |a| a[0] | a[1]);
+    interp.register("xor", 2, |a| a[0] ^ a[1]);
+    interp.register("bsprint", 1, |a| {
+        write_stdout((a[0] as i64).to_string().as_bytes())
+    });
+    interp.register("putsnl", 1, |a| {
+        let mut line = c_str(a[0]).to_bytes().to_vec();
+        line.push(b'\n');
+        write_stdout(&line)
+    });
+    interp.register("readfile", 1, |a| {
+        let path = c_str(a[0]).to_string_lossy().into_owned();
+        let mut contents = fs::read(path).unwrap_or_default();
+        contents.push(0);
+        let ptr = contents.as_ptr() as u64;
+        mem::forget(contents);
+        ptr
+    });
+    interp.register("sizeptr", 1, |_| mem::size_of::<usize>() as u64);
+
+    // libc
+    interp.register("puts", 1, |a| {
+        let mut line = c_str(a[0]).to_bytes().to_vec();
+        line.push(b'\n');
+        write_stdout(&line)
+    });
+    interp.register("putchar", 1, |a| {
+        write_stdout(&[a[0] as u8]);
+        a[0] as u8 as u64
+    });
+    interp.register("getchar", 1, |_| {
+        let mut byte = [0];
+        match io::stdin().read(&mut byte) {
+            Ok(1) => byte[0] as u64,
+            _ => -1i64 as u64,
+        }
+    });
+    interp.register("malloc", 1, |a| alloc_zeroed(a[0]));
+    interp.register("calloc", 2, |a| alloc_zeroed(a[0].wrapping_mul(a[1])));
+    interp.register_variadic("printf", 1, c_printf);
+    interp.register("memcpy", 3, |a| {
+        unsafe { ptr::copy_nonoverlapping(a[1] as *const u8, a[0] as *mut u8, a[2] as usize) };
+        a[0]
+    });
+    interp.register("memset", 3, |a| {
+        unsafe { ptr::write_bytes(a[0] as *mut u8, a[1] as u8, a[2] as usize) };
+        a[0]
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interpreter;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn run(interp: &mut Interpreter, src: &str) -> u64 {
+        let ast = crate::parse_str(src, "test.beatsaber", 0).unwrap();
+        interp.run(&ast, &["test".to_owned()]).unwrap()
+    }
+
+    #[test]
+    fn fib() {
+        let src = include_str!("../examples/fib.beatsaber");
+        assert_eq!(run(&mut Interpreter::new(), src), 55);
+    }
+
+    #[test]
+    fn multiply_goto() {
+        let src = include_str!("../examples/multiply.beatsaber");
+        assert_eq!(run(&mut Interpreter::new(), src), 20);
+    }
+
+    #[test]
+    fn n_ary() {
+        let src = "// add is not here this is big
+// memcpy is not here this is big 3
+// deref is not here
+// equalchar is not here this is big
+// sum is with a and b and c
+a.b // still in sum ab is add
+ab.c // still in sum return is add
+// a is \"abc\"
+// b is \"xyz\"
+// two is 2
+(a and b and two). // yeet is memcpy
+// x is 'x'
+a..x // eq is deref then equalchar
+// one is 1
+(eq and two and one). // return is sum
+";
+        assert_eq!(run(&mut Interpreter::new(), src), 4);
+    }
+
+    #[test]
+    fn variadic() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut interp = Interpreter::new();
+        let sink = calls.clone();
+        interp.register_variadic("log", 1, move |a| {
+            sink.borrow_mut().push(a.len());
+            0
+        });
+        let src = "// log is not here or more\n// a is 1\na. // yeet is log\na.a // yeet is log\n(a and a and a). // return is log\n";
+        run(&mut interp, src);
+        assert_eq!(*calls.borrow(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn captures() {
+        let src = include_str!("../examples/test_capture.beatsaber");
+        assert_eq!(run(&mut Interpreter::new(), src), 15);
+    }
+
+    #[test]
+    fn custom_builtin() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut interp = Interpreter::new();
+        let sink = out.clone();
+        interp.register("puts", 1, move |a| {
+            let s = unsafe { std::ffi::CStr::from_ptr(a[0] as *const _) };
+            sink.borrow_mut().push(s.to_string_lossy().into_owned());
+            0
+        });
+        let src = include_str!("../examples/hello_world.beatsaber");
+        assert_eq!(run(&mut interp, src), 0);
+        assert_eq!(*out.borrow(), ["beatsaber", "eatsaber"]);
+    }
+
+    #[test]
+    fn bf() {
+        // Per process, so concurrent test runs don't share the file
+        let name = format!("beatsaber_interp_test_{}.bf", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, "++++++++[>++++++++<-]>+.+.").unwrap();
+
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut interp = Interpreter::new();
+        let sink = out.clone();
+        interp.register("putchar", 1, move |a| {
+            sink.borrow_mut().push(a[0] as u8);
+            a[0]
+        });
+        let src = include_str!("../examples/bf.beatsaber");
+        let ast = crate::parse_str(src, "bf.beatsaber", 0).unwrap();
+        let args = ["bf".to_owned(), path.display().to_string()];
+        interp.run(&ast, &args).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(*out.borrow(), b"AB");
+    }
+
+    #[test]
+    fn invalid_goto() {
+        // Constant targets are checked by goto::resolve, argc is only known at run time
+        let src = "// a is 100\nargc // goto is\n";
+        let ast = crate::parse_str(src, "test.beatsaber", 0).unwrap();
+        let err = Interpreter::new().run(&ast, &[]).unwrap_err();
+        assert_eq!(err.to_string(), "goto to invalid line 0 at line 2");
+    }
+}
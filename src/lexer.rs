@@ -29,6 +29,8 @@ pub enum Token {
     ButIsIn,
     #[token("this is big")]
     ThisIsBig,
+    #[token("or more")]
+    OrMore,
     #[token("return")]
     Return,
     #[token("(")]
@@ -58,16 +60,25 @@ pub struct Lexer<'a> {
     peeked: Option<Option<(Token, Span)>>,
     reporter: Reporter<'a>,
     src: &'a str,
+    line: usize,
+    /// Line of the next token coming out of `inner`, ahead of `line` when a token is peeked.
+    lexed_line: usize,
+    lexed_end: usize,
+    comments: Vec<(usize, Span)>,
 }
 
 impl Iterator for Lexer<'_> {
     type Item = (Token, Span);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(next) = self.peeked.take() {
-            return next;
+        let next = match self.peeked.take() {
+            Some(next) => next,
+            None => self.lex(),
+        };
+        if let Some((Token::Newline, _)) = next {
+            self.line += 1;
         }
-        self.inner.next()
+        next
     }
 }
 
@@ -76,20 +87,80 @@ impl<'a> Lexer<'a> {
         if let Some(peeked) = &mut self.peeked {
             return peeked.clone();
         }
-        self.peeked.insert(self.inner.next()).clone()
+        let next = self.lex();
+        self.peeked.insert(next).clone()
+    }
+
+    /// Take the next token from logos, remembering the comment skipped before it if any.
+    fn lex(&mut self) -> Option<(Token, Span)> {
+        let next = self.inner.next();
+        let start = next.as_ref().map_or(self.src.len(), |(_, span)| span.start);
+        // Only whitespace and comments are skipped, and a comment always runs to the end of the line
+        let gap = &self.src[self.lexed_end..start];
+        if let Some(i) = gap.find('*') {
+            let comment_start = self.lexed_end + i;
+            let comment_end = self.lexed_end + gap.trim_end().len();
+            self.comments
+                .push((self.lexed_line, comment_start..comment_end));
+        }
+        if let Some((token, span)) = &next {
+            self.lexed_end = span.end;
+            if *token == Token::Newline {
+                self.lexed_line += 1;
+            }
+        } else {
+            self.lexed_end = self.src.len();
+        }
+        next
     }
 
-    pub fn monch(&mut self, token: Token) -> Span {
-        let (t, span) = self.next().unwrap();
+    pub fn monch(&mut self, token: Token) -> Result<Span, Diagnostic> {
+        let (t, span) = self.next_or_eof()?;
         if t != token {
-            self.reporter.report_and_exit(
-                &Diagnostic::error()
-                    .with_message("unexpected token")
-                    .with_labels(vec![Label::primary((), span)
-                        .with_message(format!("expected token `{}` here", token))]),
-            )
+            return Err(Diagnostic::error()
+                .with_message("unexpected token")
+                .with_labels(vec![Label::primary((), span)
+                    .with_message(format!("expected token `{}` here", token))]));
         }
-        span
+        Ok(span)
+    }
+
+    /// Take an identifier spelled `word`, for the parts of keywords that are not reserved.
+    pub fn monch_word(&mut self, word: &str) -> Result<Span, Diagnostic> {
+        let span = self.monch(Token::Identifier)?;
+        if &self.src[span.clone()] != word {
+            return Err(Diagnostic::error()
+                .with_message("unexpected identifier")
+                .with_labels(vec![
+                    Label::primary((), span).with_message(format!("expected `{}` here", word))
+                ]));
+        }
+        Ok(span)
+    }
+
+    /// Like `next`, but running out of tokens is an error.
+    pub fn next_or_eof(&mut self) -> Result<(Token, Span), Diagnostic> {
+        self.next().ok_or_else(|| self.eof())
+    }
+
+    /// Error for running out of tokens in the middle of a statement.
+    pub fn eof(&self) -> Diagnostic {
+        let end = self.src.len();
+        Diagnostic::error()
+            .with_message("unexpected end of file")
+            .with_labels(vec![
+                Label::primary((), end..end).with_message("statement is not finished")
+            ])
+    }
+
+    /// Line of the last consumed token, starting from 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Comments lexed so far with the line they are on, they are skipped by `next`.
+    pub fn comments(&self) -> &[(usize, Span)] {
+        &self.comments
     }
 
     pub fn reporter(&self) -> Reporter<'a> {
@@ -107,6 +178,10 @@ pub fn lexer<'a>(src: &'a str, file: &'a str) -> Lexer<'a> {
         peeked: None,
         reporter: Reporter::new(src, file),
         src,
+        line: 1,
+        lexed_line: 1,
+        lexed_end: 0,
+        comments: Vec::new(),
     }
 }
 
@@ -127,6 +202,7 @@ impl fmt::Display for Token {
             Self::ParenRight => ")",
             Self::StillIn => "still in",
             Self::And => "and",
+            Self::OrMore => "or more",
             Self::Newline => "<newline>",
             Self::Identifier => "<identifier>",
             Self::Number(_) => "<number>",
@@ -159,6 +235,39 @@ mod tests {
         assert_eq!(lex.next(), Some(Token::Newline));
     }
 
+    #[test]
+    fn or_more() {
+        let mut lex = Token::lexer("// printf is not here or more\na.b // c is or\n");
+        assert_eq!(lex.next(), Some(Token::BehaviourStart));
+        assert_eq!(lex.next(), Some(Token::Identifier));
+        assert_eq!(lex.next(), Some(Token::Is));
+        assert_eq!(lex.next(), Some(Token::NotHere));
+        assert_eq!(lex.next(), Some(Token::OrMore));
+        assert_eq!(lex.next(), Some(Token::Newline));
+        lex.nth(5);
+        assert_eq!(lex.next(), Some(Token::Identifier));
+        assert_eq!(lex.slice(), "or");
+    }
+
+    #[test]
+    fn here_is_a_name() {
+        // Labels and `here for all` are recognised by the parser, `here` is not reserved
+        let mut lex = Token::lexer(
+            "// here is loop
+// here is 1
+",
+        );
+        assert_eq!(lex.next(), Some(Token::BehaviourStart));
+        assert_eq!(lex.next(), Some(Token::Identifier));
+        assert_eq!(lex.slice(), "here");
+        assert_eq!(lex.next(), Some(Token::Is));
+        assert_eq!(lex.next(), Some(Token::Identifier));
+        assert_eq!(lex.next(), Some(Token::Newline));
+        lex.next();
+        assert_eq!(lex.next(), Some(Token::Identifier));
+        assert_eq!(lex.slice(), "here");
+    }
+
     #[test]
     fn complex_lex() {
         let mut lex = Token::lexer(
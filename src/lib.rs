@@ -1,5 +1,97 @@
 pub mod ast1;
 pub mod ast2;
+#[cfg(feature = "llvm")]
 pub mod codegen;
 mod error;
+pub mod fmt;
+pub mod goto;
+pub mod interp;
 pub mod lexer;
+pub mod link;
+pub mod prelude;
+#[cfg(feature = "llvm")]
+mod runtime;
+pub mod symbols;
+
+pub use error::{Diagnostic, Label, Reporter};
+
+/// Number of errors reported before the compiler gives up on a file.
+pub const DEFAULT_ERROR_LIMIT: usize = 20;
+
+/// Lex, parse and resolve a program, returning the diagnostics instead of reporting them.
+///
+/// At most `error_limit` errors are collected, 0 means no limit.
+pub fn parse_str(
+    src: &str,
+    name: &str,
+    error_limit: usize,
+) -> Result<Vec<ast2::DecoratedStmt>, Vec<Diagnostic>> {
+    parse_str_with_prelude(src, name, error_limit, prelude::FUNCTIONS)
+}
+
+/// Like `parse_str`, with only the functions of `prelude` available without a declaration.
+/// With `--no-std` the prelude is empty.
+pub fn parse_str_with_prelude(
+    src: &str,
+    name: &str,
+    error_limit: usize,
+    prelude: &[prelude::Function],
+) -> Result<Vec<ast2::DecoratedStmt>, Vec<Diagnostic>> {
+    let lexer = lexer::lexer(src, name);
+    let parser = ast1::parser(lexer);
+    let mut ast = ast2::parse(parser, error_limit, prelude)?;
+    goto::resolve(&mut ast)?;
+    Ok(ast)
+}
+
+/// Why `compile_str` failed.
+#[cfg(feature = "llvm")]
+#[derive(Debug)]
+pub enum CompileError {
+    /// Errors in the program, as returned by `parse_str`
+    Parse(Vec<Diagnostic>),
+    /// Code generation or the build failed, a failed C compiler run downcasts to
+    /// `codegen::BuildError`
+    Build(anyhow::Error),
+}
+
+#[cfg(feature = "llvm")]
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CompileError::Parse(diagnostics) => {
+                let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+                write!(f, "{}", messages.join("\n"))
+            }
+            CompileError::Build(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+#[cfg(feature = "llvm")]
+impl std::error::Error for CompileError {}
+
+/// Compile a program to the outputs requested in `options`, like `bsc` does.
+///
+/// The prelude is left out without `options.std`, and the libraries named by `but is in` are
+/// searched next to `name` and in `options.library_paths`.
+#[cfg(feature = "llvm")]
+pub fn compile_str(
+    src: &str,
+    name: &str,
+    error_limit: usize,
+    mut options: codegen::CodegenOptions,
+) -> Result<codegen::Artifact, CompileError> {
+    let prelude = if options.std { prelude::FUNCTIONS } else { &[] };
+    let ast =
+        parse_str_with_prelude(src, name, error_limit, prelude).map_err(CompileError::Parse)?;
+    let dirs = link::search_dirs(std::path::Path::new(name), &options.library_paths);
+    let libraries =
+        link::resolve(&ast, &dirs, options.target.as_deref()).map_err(CompileError::Parse)?;
+    options.libraries.extend(libraries);
+    let warnings = goto::lint(&ast);
+    let mut artifact = codegen::Codegen::compile(ast, std::path::Path::new(name), options)
+        .map_err(CompileError::Build)?;
+    artifact.warnings = warnings;
+    Ok(artifact)
+}
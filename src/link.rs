@@ -0,0 +1,234 @@
+//! Libraries named with `but is in`.
+//!
+//! They are looked up before codegen, so a missing library is reported at the declaration
+//! naming it instead of as a linker error. A module is looked up as written if it has an
+//! extension or a path, `m` is looked up as `libm.so`, `libm.a` and `libm.dylib`.
+
+use crate::ast2::{Callable, DecoratedStmt, ExternFunction};
+use crate::error::{Diagnostic, Label};
+use logos::Span;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A library to link, or to load before running a program in-process.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Library {
+    /// Name as written after `but is in`
+    pub module: String,
+    pub path: PathBuf,
+    /// Found by the C compiler in its own search path, rather than in one of the directories
+    /// passed to `resolve`
+    pub system: bool,
+}
+
+impl Library {
+    /// Whether the library is loaded at run time rather than copied into the executable.
+    pub fn is_shared(&self) -> bool {
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        name.ends_with(".dylib") || name.ends_with(".dll") || name.contains(".so")
+    }
+
+    /// Arguments adding the library to a C compiler link line.
+    pub fn link_args(&self) -> Vec<String> {
+        if self.system && !is_file_name(&self.module) {
+            return vec![format!("-l{}", self.module)];
+        }
+        let mut args = vec![self.path.display().to_string()];
+        if self.is_shared() && !self.system {
+            // Found where the dynamic loader won't look
+            if let Some(dir) = self.path.parent() {
+                args.push(format!("-Wl,-rpath,{}", dir.display()));
+            }
+        }
+        args
+    }
+}
+
+/// Whether a module names a file rather than a library, like `libthing.so` rather than `thing`.
+fn is_file_name(module: &str) -> bool {
+    module.contains(['.', '/', '\\'].as_ref())
+}
+
+/// Files a module could be.
+fn candidates(module: &str) -> Vec<String> {
+    if is_file_name(module) {
+        vec![module.to_owned()]
+    } else {
+        ["so", "a", "dylib"]
+            .iter()
+            .map(|extension| format!("lib{}.{}", module, extension))
+            .collect()
+    }
+}
+
+/// Ask the C compiler for `target` where it would find a file when linking.
+fn system_path(file: &str, target: Option<&str>) -> Option<PathBuf> {
+    let compiler = cc::Build::new()
+        .target(target.unwrap_or(env!("HOST")))
+        .host(env!("HOST"))
+        .opt_level(0)
+        .cargo_metadata(false)
+        .try_get_compiler()
+        .ok()?;
+    if compiler.is_like_msvc() {
+        return None;
+    }
+    let output = Command::new(compiler.path())
+        .arg(format!("-print-file-name={}", file))
+        .output()
+        .ok()?;
+    // The file name is echoed back as is when it isn't found
+    let path = PathBuf::from(String::from_utf8(output.stdout).ok()?.trim());
+    if output.status.success() && path.is_absolute() && path.exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Look up a module in `dirs`, then in the search path of the C compiler.
+fn find(module: &str, dirs: &[PathBuf], target: Option<&str>) -> Option<Library> {
+    let candidates = candidates(module);
+    for dir in dirs {
+        for candidate in &candidates {
+            let path = dir.join(candidate);
+            if path.is_file() {
+                return Some(Library {
+                    module: module.to_owned(),
+                    path: path.canonicalize().unwrap_or(path),
+                    system: false,
+                });
+            }
+        }
+    }
+    if module.contains(['/', '\\'].as_ref()) {
+        return None;
+    }
+    candidates.iter().find_map(|candidate| {
+        Some(Library {
+            module: module.to_owned(),
+            path: system_path(candidate, target)?,
+            system: true,
+        })
+    })
+}
+
+fn not_found(module: &str, span: Span, dirs: &[PathBuf]) -> Diagnostic {
+    let searched = dirs
+        .iter()
+        .map(|dir| format!("`{}`", dir.display()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Diagnostic::error()
+        .with_message("library not found")
+        .with_labels(vec![Label::primary((), span).with_message(format!(
+            "`{}` is not in {} or the library path of the C compiler",
+            module, searched
+        ))])
+        .with_notes(vec![
+            "pass the directory containing it with `-L`".to_string()
+        ])
+}
+
+/// Find the library of every `but is in`, in the order they are declared.
+///
+/// `dirs` are searched first, usually the directory of the source and the `-L` directories.
+/// Relative paths in module names are relative to them. `target` is the target triple
+/// libraries are linked for, None for the host.
+pub fn resolve(
+    ast: &[DecoratedStmt],
+    dirs: &[PathBuf],
+    target: Option<&str>,
+) -> Result<Vec<Library>, Vec<Diagnostic>> {
+    let mut libraries: Vec<Library> = Vec::new();
+    let mut errors = Vec::new();
+    for stmt in ast {
+        let (module, span) = match stmt {
+            DecoratedStmt::Callable(Callable::ExternFunction(ExternFunction {
+                module: Some(module),
+                ..
+            })) => module,
+            _ => continue,
+        };
+        if libraries.iter().any(|library| &library.module == module) {
+            continue;
+        }
+        match find(module, dirs, target) {
+            Some(library) => libraries.push(library),
+            None => errors.push(not_found(module, span.clone(), dirs)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(libraries)
+    } else {
+        errors.sort_by_key(|error| error.labels[0].range.start);
+        Err(errors)
+    }
+}
+
+/// Directories searched for the libraries of `source`: its own directory, then `library_paths`.
+pub fn search_dirs(source: &Path, library_paths: &[PathBuf]) -> Vec<PathBuf> {
+    let dir = match source.parent() {
+        Some(dir) if dir != Path::new("") => dir.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    let mut dirs = vec![dir];
+    dirs.extend(library_paths.iter().cloned());
+    dirs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve;
+    use std::fs;
+
+    #[test]
+    fn local_library() {
+        let dir = std::env::temp_dir()
+            .join(format!("beatsaber_link_test_{}", std::process::id()))
+            .join("local_library");
+        fs::create_dir_all(&dir).unwrap();
+        let c = dir.join("thing.c");
+        fs::write(
+            &c,
+            "unsigned long twice(unsigned long a) { return a * 2; }\n",
+        )
+        .unwrap();
+        let compiler = cc::Build::new()
+            .target(env!("HOST"))
+            .host(env!("HOST"))
+            .opt_level(0)
+            .cargo_metadata(false)
+            .get_compiler();
+        let status = compiler
+            .to_command()
+            .args(["-shared", "-fPIC", "-o"])
+            .arg(dir.join("libthing.so"))
+            .arg(&c)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let src = "// twice is not here but is in libthing.so\n// half is not here but is in libthing.so\n// cos is not here but is in m\n";
+        let ast = crate::parse_str(src, "test.beatsaber", 0).unwrap();
+        let libraries = resolve(&ast, std::slice::from_ref(&dir), None).unwrap();
+        assert_eq!(libraries.len(), 2);
+        let thing = libraries
+            .iter()
+            .find(|lib| lib.module == "libthing.so")
+            .unwrap();
+        assert_eq!(thing.path, dir.canonicalize().unwrap().join("libthing.so"));
+        assert!(!thing.system);
+        assert!(thing.link_args()[1].starts_with("-Wl,-rpath,"));
+        let m = libraries.iter().find(|lib| lib.module == "m").unwrap();
+        assert!(m.system);
+        assert_eq!(m.link_args(), ["-lm"]);
+
+        let src = "// f is not here but is in libmissing.so\n// g is not here but is in \"./libthing.so\"\n";
+        let ast = crate::parse_str(src, "test.beatsaber", 0).unwrap();
+        let errors = resolve(&ast, &[dir], None).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "library not found");
+        assert_eq!(&src[errors[0].labels[0].range.clone()], "libmissing.so");
+    }
+}
@@ -0,0 +1,50 @@
+//! Functions of the standard library, stdlib.c, that programs can call without declaring them.
+//!
+//! The compiler links stdlib.c into every program that uses one of them, unless it is run with
+//! `--no-std`. They are declared before the first line of the file, so nothing in the file can
+//! shadow them, but a matching `not here` declaration is still accepted.
+
+/// A function of the standard library.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Function {
+    pub name: &'static str,
+    pub params: usize,
+}
+
+macro_rules! prelude {
+    ($($name:ident($params:literal)),* $(,)?) => {
+        /// Every function in the prelude, in the order stdlib.c defines them.
+        pub const FUNCTIONS: &[Function] = &[$(Function {
+            name: stringify!($name),
+            params: $params,
+        }),*];
+    };
+}
+
+// `and` is left out, it is a keyword and can't be named by a program
+prelude! {
+    inc(1),
+    dec(1),
+    store(2),
+    storeb(2),
+    add(2),
+    sub(2),
+    greater(2),
+    less(2),
+    deref(1),
+    equal(2),
+    equalchar(2),
+    bnot(1),
+    not(1),
+    or(2),
+    xor(2),
+    bsprint(1),
+    putsnl(1),
+    readfile(1),
+    sizeptr(1),
+}
+
+/// Look up a function of the prelude.
+pub fn get(name: &str) -> Option<&'static Function> {
+    FUNCTIONS.iter().find(|function| function.name == name)
+}
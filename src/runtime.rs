@@ -0,0 +1,45 @@
+//! The helpers from stdlib.c and the hooks from runtime.c, compiled into the compiler itself by
+//! the build script so JIT compiled programs can call them without a C compiler.
+
+macro_rules! runtime {
+    ($($name:ident($($param:ident),*)),* $(,)?) => {
+        extern "C" {
+            $(fn $name($($param: usize),*) -> usize;)*
+        }
+
+        /// Name and address of every runtime function.
+        pub fn symbols() -> Vec<(&'static str, usize)> {
+            vec![$((stringify!($name), $name as *const () as usize)),*]
+        }
+    };
+}
+
+runtime! {
+    inc(a),
+    dec(a),
+    store(ptr, val),
+    storeb(ptr, val),
+    add(a, b),
+    sub(a, b),
+    greater(a, b),
+    less(a, b),
+    deref(ptr),
+    equal(x, y),
+    equalchar(x, y),
+    bnot(x),
+    not(x),
+    and(x, y),
+    or(x, y),
+    xor(x, y),
+    bsprint(a),
+    putsnl(ptr),
+    readfile(path),
+    sizeptr(unused),
+    bs_goto_failed(target, line),
+}
+
+/// Source of the hooks that compiled code calls, linked into every executable.
+pub const HOOKS: &str = include_str!("../runtime.c");
+
+/// Source of the standard library, linked into executables that use the prelude.
+pub const STDLIB: &str = include_str!("../stdlib.c");
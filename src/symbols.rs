@@ -0,0 +1,306 @@
+//! Declarations and uses of every name in a program, for editor tooling.
+//!
+//! Names are scoped the same way `ast2::parse` binds them: a scope for the file and one for
+//! each function body. The first assignment to a name in a scope declares it, later
+//! assignments in that scope reuse the same symbol. The functions of the prelude are declared
+//! before the first line.
+
+use crate::ast1::{self, AssignTarget, AssignValue, Behaviour, Expr, Op};
+use crate::{lexer, prelude};
+use logos::Span;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SymbolKind {
+    /// Declared with `with`, a `FuncBlock` in ast2.
+    Function {
+        params: usize,
+    },
+    /// Declared with `not here`, two parameters when `this is big`.
+    Extern {
+        params: usize,
+        module: Option<String>,
+        /// `or more`, `params` is the minimum
+        variadic: bool,
+    },
+    Value,
+    /// Declared with `here is`, `line` is the line it names.
+    Label,
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    /// Span of the name where it is declared.
+    pub decl: Span,
+    /// 0 for functions of the prelude that the file does not declare
+    pub line: usize,
+    pub kind: SymbolKind,
+}
+
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+    /// Names declared in each scope, the file scope first and then one per function body.
+    scopes: Vec<HashMap<String, usize>>,
+    /// Function symbol -> scope of its body
+    bodies: HashMap<usize, usize>,
+    /// Every occurrence of a name, including declarations, with the symbol it refers to.
+    references: Vec<(Span, usize)>,
+}
+
+const FILE_SCOPE: usize = 0;
+
+impl Default for Symbols {
+    fn default() -> Self {
+        Symbols {
+            symbols: Vec::new(),
+            scopes: vec![HashMap::new()],
+            bodies: HashMap::new(),
+            references: Vec::new(),
+        }
+    }
+}
+
+impl Symbols {
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    /// Symbol declared as `name` outside of any function.
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.scopes[FILE_SCOPE].get(name).map(|&i| &self.symbols[i])
+    }
+
+    /// Symbol referred to by the name at byte offset `offset`.
+    pub fn at(&self, offset: usize) -> Option<&Symbol> {
+        self.references
+            .iter()
+            .find(|(span, _)| span.start <= offset && offset <= span.end)
+            .map(|&(_, i)| &self.symbols[i])
+    }
+
+    fn lookup(&self, scope: usize, name: &str) -> Option<usize> {
+        self.scopes[scope]
+            .get(name)
+            .or_else(|| self.scopes[FILE_SCOPE].get(name))
+            .copied()
+    }
+
+    fn declare(&mut self, src: &str, scope: usize, span: Span, line: usize, kind: SymbolKind) {
+        let name = &src[span.clone()];
+        let symbol = Symbol {
+            name: name.to_owned(),
+            decl: span.clone(),
+            line,
+            kind,
+        };
+        let index = match self.scopes[scope].get(name).copied() {
+            // Functions shadow values instead of assigning them
+            Some(index) if symbol.kind == SymbolKind::Value => index,
+            // Declaring a function of the prelude again takes its place
+            Some(index)
+                if self.symbols[index].line == 0
+                    && matches!(symbol.kind, SymbolKind::Extern { .. }) =>
+            {
+                self.symbols[index] = symbol;
+                index
+            }
+            _ => {
+                self.symbols.push(symbol);
+                self.scopes[scope].insert(name.to_owned(), self.symbols.len() - 1);
+                self.symbols.len() - 1
+            }
+        };
+        self.references.push((span, index));
+    }
+
+    fn reference(&mut self, src: &str, scope: usize, span: Span) {
+        if let Some(index) = self.lookup(scope, &src[span.clone()]) {
+            self.references.push((span, index));
+        }
+    }
+
+    fn expr(&mut self, src: &str, scope: usize, expr: &Expr) {
+        match expr {
+            Expr::Binop { lhs, rhs, .. } => {
+                self.expr(src, scope, lhs);
+                self.expr(src, scope, rhs);
+            }
+            Expr::Unop { expr, .. } | Expr::Paren { expr, .. } => self.expr(src, scope, expr),
+            Expr::Args { args, .. } => {
+                for arg in args {
+                    self.expr(src, scope, arg);
+                }
+            }
+            Expr::Ident(span) => self.reference(src, scope, span.clone()),
+        }
+    }
+
+    fn ops(&mut self, src: &str, scope: usize, ops: &[Op]) {
+        for op in ops {
+            self.reference(src, scope, op.ident.clone());
+        }
+    }
+
+    /// Index a behaviour, along with the expression of its line once the scope it is
+    /// evaluated in is known.
+    fn behaviour(
+        &mut self,
+        src: &str,
+        scope: usize,
+        line: usize,
+        behaviour: &Behaviour,
+        expr: Option<&Expr>,
+    ) {
+        match behaviour {
+            Behaviour::StillIn {
+                ident, behaviour, ..
+            } => {
+                self.reference(src, scope, ident.clone());
+                let body = self
+                    .lookup(scope, &src[ident.clone()])
+                    .and_then(|function| self.bodies.get(&function).copied())
+                    .unwrap_or(scope);
+                self.behaviour(src, body, line, behaviour, expr);
+            }
+            Behaviour::Cond {
+                cond, behaviour, ..
+            } => {
+                self.reference(src, scope, cond.clone());
+                self.behaviour(src, scope, line, behaviour, expr);
+            }
+            Behaviour::Label { name, .. } => {
+                self.declare(src, scope, name.clone(), line, SymbolKind::Label)
+            }
+            Behaviour::Assign { target, value, .. } => match value {
+                AssignValue::Fn(f) => {
+                    let kind = SymbolKind::Function {
+                        params: f.params.names.len(),
+                    };
+                    let body = self.scopes.len();
+                    self.scopes.push(HashMap::new());
+                    if let AssignTarget::Ident(span) = target {
+                        self.declare(src, scope, span.clone(), line, kind);
+                        self.bodies.insert(self.symbols.len() - 1, body);
+                    }
+                    for name in &f.params.names {
+                        self.declare(src, body, name.clone(), line, SymbolKind::Value);
+                    }
+                    if let Some(expr) = expr {
+                        self.expr(src, body, expr);
+                    }
+                    self.ops(src, body, &f.ops);
+                }
+                _ => {
+                    // Names in the expression are bound before the behaviour assigns anything
+                    if let Some(expr) = expr {
+                        self.expr(src, scope, expr);
+                    }
+                    if let AssignValue::Ops(ops) = value {
+                        self.ops(src, scope, ops);
+                    }
+                    let kind = match value {
+                        AssignValue::NotHere(not_here) => SymbolKind::Extern {
+                            params: not_here.param_count(),
+                            module: not_here.module(src).map(str::to_owned),
+                            variadic: not_here.or_more.is_some(),
+                        },
+                        _ => SymbolKind::Value,
+                    };
+                    if let AssignTarget::Ident(span) = target {
+                        self.declare(src, scope, span.clone(), line, kind);
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Collect the symbols of a program, skipping statements that do not parse.
+pub fn index(src: &str) -> Symbols {
+    let mut symbols = Symbols::default();
+    for function in prelude::FUNCTIONS {
+        symbols.symbols.push(Symbol {
+            name: function.name.to_owned(),
+            decl: 0..0,
+            line: 0,
+            kind: SymbolKind::Extern {
+                params: function.params,
+                module: None,
+                variadic: false,
+            },
+        });
+        let index = symbols.symbols.len() - 1;
+        symbols.scopes[FILE_SCOPE].insert(function.name.to_owned(), index);
+    }
+    for stmt in ast1::parser(lexer::lexer(src, "")).flatten() {
+        symbols.behaviour(
+            src,
+            FILE_SCOPE,
+            stmt.line,
+            &stmt.behaviour,
+            stmt.expr.as_ref(),
+        );
+    }
+    symbols
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{index, SymbolKind};
+
+    const FIB: &str = "// add is not here this is big
+// sub is not here this is big
+// fib is with n
+// still in fib one is 1
+n.one // still in fib return is sub then fib
+// res is 10
+res. // yeet is fib
+";
+
+    #[test]
+    fn kinds() {
+        let symbols = index(FIB);
+        assert_eq!(
+            symbols.get("add").unwrap().kind,
+            SymbolKind::Extern {
+                params: 2,
+                module: None,
+                variadic: false,
+            }
+        );
+        assert_eq!(
+            symbols.get("fib").unwrap().kind,
+            SymbolKind::Function { params: 1 }
+        );
+        // Parameters are only declared in the scope of their function
+        assert!(symbols.get("n").is_none());
+        let n = symbols.at(FIB.find("n.").unwrap()).unwrap();
+        assert_eq!(n.kind, SymbolKind::Value);
+        assert_eq!(symbols.get("res").unwrap().line, 6);
+        // Declared again by the file, and only in the prelude
+        assert_eq!(symbols.get("add").unwrap().line, 1);
+        assert_eq!(symbols.iter().filter(|s| s.name == "add").count(), 1);
+        assert_eq!(symbols.get("inc").unwrap().line, 0);
+    }
+
+    #[test]
+    fn references() {
+        let symbols = index(FIB);
+        let use_of_fib = FIB.rfind("fib").unwrap();
+        let fib = symbols.at(use_of_fib).unwrap();
+        assert_eq!(fib.name, "fib");
+        assert_eq!(&FIB[fib.decl.clone()], "fib");
+        assert_eq!(fib.decl.start, FIB.find("fib").unwrap());
+    }
+
+    #[test]
+    fn scopes() {
+        let src = "// n is 1\n// f is with n\nn. // still in f return is f\n";
+        let symbols = index(src);
+        // The parameter shadows the global inside the function
+        let use_of_n = src.rfind("n.").unwrap();
+        assert_eq!(symbols.at(use_of_n).unwrap().line, 2);
+        assert_eq!(symbols.get("n").unwrap().line, 1);
+    }
+}